@@ -0,0 +1,260 @@
+use crate::{
+    config::load_config_from,
+    model::{Config, Server},
+};
+use chrono::{Local, NaiveDateTime};
+use serde_yaml::Value;
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+use tabled::Tabled;
+
+/// Older backups are deleted once there are more than this.
+const MAX_BACKUPS: usize = 20;
+const TIME_FORMAT: &str = "%Y%m%d-%H%M%S%.3f";
+
+/// A previous version of the config file.
+pub(crate) struct Backup {
+    pub(crate) path: PathBuf,
+    pub(crate) time: NaiveDateTime,
+}
+
+impl Backup {
+    pub(crate) fn load(&self) -> anyhow::Result<Config> {
+        let content = fs::read_to_string(&self.path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", self.path.display(), e))?;
+        load_config_from(&content)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", self.path.display(), e))
+    }
+}
+
+/// Backups of `config.yaml` live in `config.yaml.backups` next to it.
+fn backup_dir(config_path: &Path) -> PathBuf {
+    let mut dir = OsString::from(config_path.as_os_str());
+    dir.push(".backups");
+    PathBuf::from(dir)
+}
+
+/// Copies the config file at `config_path` into the backups before
+/// `replacement` replaces it. Saves that only change which server is current
+/// are left out, every connection makes one of those.
+pub(crate) fn backup_config(config_path: &Path, replacement: &str) -> anyhow::Result<()> {
+    let Ok(content) = fs::read_to_string(config_path) else {
+        return Ok(());
+    };
+    if let (Some(previous), Some(replacement)) =
+        (without_current(&content), without_current(replacement))
+        && previous == replacement
+    {
+        return Ok(());
+    }
+
+    let dir = backup_dir(config_path);
+    fs::create_dir_all(&dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    }
+
+    let name = format!("{}.yaml", Local::now().format(TIME_FORMAT));
+    let mut options = fs::OpenOptions::new();
+    options.create(true).truncate(true).write(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    std::io::Write::write_all(&mut options.open(dir.join(name))?, content.as_bytes())?;
+
+    for backup in list_backups(config_path).into_iter().skip(MAX_BACKUPS) {
+        fs::remove_file(backup.path)?;
+    }
+    Ok(())
+}
+
+/// The config document with `current` left out of every server.
+fn without_current(content: &str) -> Option<Value> {
+    let mut value: Value = serde_yaml::from_str(content).ok()?;
+    if let Some(servers) = value.get_mut("servers").and_then(Value::as_sequence_mut) {
+        for server in servers.iter_mut().filter_map(Value::as_mapping_mut) {
+            server.remove("current");
+        }
+    }
+    Some(value)
+}
+
+/// Backups of the config file, newest first.
+pub(crate) fn list_backups(config_path: &Path) -> Vec<Backup> {
+    let Ok(entries) = fs::read_dir(backup_dir(config_path)) else {
+        return vec![];
+    };
+
+    let mut backups: Vec<Backup> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let time = name.strip_suffix(".yaml")?;
+            let time = NaiveDateTime::parse_from_str(time, TIME_FORMAT).ok()?;
+            Some(Backup {
+                path: entry.path(),
+                time,
+            })
+        })
+        .collect();
+    backups.sort_by_key(|backup| std::cmp::Reverse(backup.time));
+    backups
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ServerChange {
+    Added(String),
+    Removed(String),
+    /// The server's name and the settings that changed.
+    Changed(String, Vec<String>),
+}
+
+impl std::fmt::Display for ServerChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerChange::Added(name) => write!(f, "+{}", name),
+            ServerChange::Removed(name) => write!(f, "-{}", name),
+            ServerChange::Changed(name, fields) => write!(f, "~{}({})", name, fields.join(", ")),
+        }
+    }
+}
+
+/// What changes going from `from` to `to`, ignoring which server is current.
+pub(crate) fn diff_servers(from: &Config, to: &Config) -> Vec<ServerChange> {
+    let find = |config: &Config, name: &str| -> Option<Server> {
+        config.servers.iter().find(|s| s.name == name).cloned()
+    };
+
+    let mut changes = vec![];
+    for server in &from.servers {
+        match find(to, &server.name) {
+            None => changes.push(ServerChange::Removed(server.name.clone())),
+            Some(other) => {
+                let fields = changed_fields(server, &other);
+                if !fields.is_empty() {
+                    changes.push(ServerChange::Changed(server.name.clone(), fields));
+                }
+            }
+        }
+    }
+    for server in &to.servers {
+        if find(from, &server.name).is_none() {
+            changes.push(ServerChange::Added(server.name.clone()));
+        }
+    }
+    changes
+}
+
+/// The sections besides `servers` that differ between `from` and `to`.
+pub(crate) fn changed_sections(from: &Config, to: &Config) -> Vec<&'static str> {
+    let mut sections = vec![];
+    if from.include != to.include {
+        sections.push("include");
+    }
+    if from.defaults != to.defaults {
+        sections.push("defaults");
+    }
+    if from.groups != to.groups {
+        sections.push("groups");
+    }
+    sections
+}
+
+fn changed_fields(from: &Server, to: &Server) -> Vec<String> {
+    let mapping = |server: &Server| match serde_yaml::to_value(server) {
+        Ok(serde_yaml::Value::Mapping(mapping)) => mapping,
+        _ => Default::default(),
+    };
+    let (from, to) = (mapping(from), mapping(to));
+
+    let mut fields: Vec<String> = from
+        .keys()
+        .chain(to.keys())
+        .filter(|key| from.get(*key) != to.get(*key))
+        .filter_map(|key| key.as_str())
+        .filter(|key| *key != "current")
+        .map(str::to_string)
+        .collect();
+    fields.sort();
+    fields.dedup();
+    fields
+}
+
+#[derive(Debug, Clone, Tabled)]
+#[tabled(rename_all = "UPPERCASE")]
+pub(crate) struct HistoryEntry {
+    #[tabled(rename = "#")]
+    pub(crate) index: usize,
+    pub(crate) saved: String,
+    pub(crate) servers: usize,
+    /// What the save after this version changed.
+    #[tabled(rename = "CHANGED AFTERWARDS")]
+    pub(crate) changes: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    const CONFIG: &str = "
+defaults:
+  port: 22
+servers:
+- name: a
+  host: 10.0.0.1
+  user: root
+  current: true
+- name: b
+  host: 10.0.0.2
+  user: root
+";
+
+    #[test]
+    fn server_changes_are_listed() {
+        let from = parse_config(CONFIG, &[]).unwrap();
+        let mut to = from.clone();
+        to.servers[0].user = "deploy".to_string();
+        to.servers[0].current = None;
+        to.servers[1].name = "c".to_string();
+
+        assert_eq!(
+            diff_servers(&from, &to),
+            [
+                ServerChange::Changed("a".to_string(), vec!["user".to_string()]),
+                ServerChange::Removed("b".to_string()),
+                ServerChange::Added("c".to_string()),
+            ]
+        );
+        assert!(diff_servers(&from, &from).is_empty());
+    }
+
+    #[test]
+    fn section_changes_are_listed() {
+        let from = parse_config(CONFIG, &[]).unwrap();
+        let mut to = from.clone();
+        to.defaults.insert("user".into(), "deploy".into());
+        to.include.push("team.yaml".to_string());
+
+        assert_eq!(changed_sections(&from, &to), ["include", "defaults"]);
+        assert!(changed_sections(&from, &from).is_empty());
+    }
+
+    #[test]
+    fn current_is_ignored_when_comparing_files() {
+        let switched = CONFIG
+            .replace("  current: true\n", "")
+            .replace("host: 10.0.0.2\n", "host: 10.0.0.2\n  current: true\n");
+        assert_eq!(without_current(CONFIG), without_current(&switched));
+
+        let changed = CONFIG.replace("port: 22", "port: 2222");
+        assert_ne!(without_current(CONFIG), without_current(&changed));
+    }
+}
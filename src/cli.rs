@@ -1,16 +1,25 @@
 #![allow(dead_code)]
+#[cfg(unix)]
+use crate::cmd::{mux_master, mux_status, mux_stop};
 use crate::{
     cmd::{
-        add_server, connect_server, edit_server, import_servers, list_servers, remove_server,
-        rename_server, version,
+        NewServer, Overrides, add_server, add_server_with, cluster_servers, config_history,
+        connect_server, edit_remote_file, edit_server, exec_servers, get_files, import_servers,
+        list_servers, play_recording, put_files, remove_server, rename_server, sftp_shell,
+        show_server, sync_dirs, undo_config, version,
     },
-    common::{print_completions, server_completer, servers_len},
+    common::{group_completer, print_completions, server_completer, servers_len, tag_completer},
+    config::set_config_path,
+    model::ServerFilter,
+    sftp::TransferOptions,
+    sync::SyncOptions,
 };
 use clap::{
     ArgAction, Args, CommandFactory, Parser, Subcommand, ValueHint,
     builder::{Styles, styling::AnsiColor},
 };
 use clap_complete::{ArgValueCompleter, CompleteEnv, Shell};
+use std::ffi::OsString;
 
 #[derive(Debug, Parser)]
 #[command(
@@ -27,6 +36,14 @@ pub struct Cli {
     #[command(subcommand)]
     command: Option<SubCommands>,
 
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Config file to use, default is $SSHER_CONFIG or $XDG_CONFIG_HOME/ssher/config.yaml",
+        value_hint = ValueHint::FilePath,
+    )]
+    config: Option<String>,
+
     #[arg(
         short,
         long,
@@ -34,6 +51,67 @@ pub struct Cli {
         add = ArgValueCompleter::new(server_completer),
     )]
     server: Option<String>,
+
+    #[arg(
+        help = "Server name or destination e.g. user@host:port, ssh://user@host:port",
+        conflicts_with = "server",
+        add = ArgValueCompleter::new(server_completer),
+    )]
+    target: Option<String>,
+
+    #[arg(short = 'l', long, help = "User to log in as")]
+    user: Option<String>,
+
+    #[arg(short, long, help = "Port to connect to")]
+    port: Option<u16>,
+
+    #[arg(
+        short,
+        long,
+        help = "Identity file to authenticate with",
+        value_hint = ValueHint::FilePath,
+    )]
+    identity: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Append the session output to a log file, %n and %d expand to the server name and date",
+        value_hint = ValueHint::FilePath,
+    )]
+    log: Option<String>,
+
+    #[arg(short = 'A', long, help = "Forward the local ssh-agent to the server")]
+    forward_agent: bool,
+
+    #[arg(short = 'X', long, help = "Enable untrusted X11 forwarding")]
+    forward_x11: bool,
+
+    #[arg(short = 'Y', long, help = "Enable trusted X11 forwarding")]
+    forward_x11_trusted: bool,
+
+    #[arg(
+        long = "env",
+        value_name = "KEY=VAL",
+        help = "Set an environment variable on the server, can be repeated",
+        value_parser = parse_key_val,
+    )]
+    env: Vec<(String, String)>,
+
+    #[arg(
+        short = 'o',
+        value_name = "KEY=VALUE",
+        help = "Override a server setting for this connection e.g. -o remote_dir=/srv, can be repeated",
+        value_parser = parse_key_val,
+    )]
+    options: Vec<(String, String)>,
+}
+
+fn parse_key_val(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .filter(|(key, _)| !key.is_empty())
+        .map(|(key, val)| (key.to_string(), val.to_string()))
+        .ok_or_else(|| format!("invalid KEY=VAL: no `=` found in `{}`", s))
 }
 
 #[derive(Debug, Subcommand)]
@@ -55,21 +133,21 @@ enum SubCommands {
         command: Option<CompletionSubCommands>,
     },
     #[command(name = "add", about = "Add a new server", disable_help_flag = true)]
-    Add,
+    Add(AddArgs),
     #[command(
         name = "list",
         about = "List all servers",
         visible_alias = "ls",
         disable_help_flag = true
     )]
-    List,
+    List(FilterArgs),
     #[command(
         name = "edit",
         about = "Edit a server",
         allow_missing_positional = true,
         disable_help_flag = true
     )]
-    Edit(ServerArgs),
+    Edit(EditArgs),
     #[command(
         name = "remove",
         about = "Remove a server or servers",
@@ -85,6 +163,13 @@ enum SubCommands {
         disable_help_flag = true
     )]
     Rename(ServerArgs),
+    #[command(
+        name = "show",
+        about = "Show the settings of a server",
+        allow_missing_positional = true,
+        disable_help_flag = true
+    )]
+    Show(ShowArgs),
     #[command(
         name = "import",
         about = "Import servers from ssh config file eg. ~/.ssh/config",
@@ -92,6 +177,109 @@ enum SubCommands {
         disable_help_flag = true
     )]
     Import(ImportArgs),
+    #[command(
+        name = "cluster",
+        about = "Broadcast input to several servers at once",
+        allow_missing_positional = true,
+        disable_help_flag = true
+    )]
+    Cluster(ClusterArgs),
+    #[command(
+        name = "exec",
+        about = "Run a command on several servers in parallel",
+        disable_help_flag = true
+    )]
+    Exec(ExecArgs),
+    #[command(
+        name = "get",
+        about = "Download files from a server over sftp",
+        disable_help_flag = true
+    )]
+    Get(GetArgs),
+    #[command(
+        name = "put",
+        about = "Upload files to a server over sftp",
+        disable_help_flag = true
+    )]
+    Put(PutArgs),
+    #[command(
+        name = "sftp",
+        about = "Browse a server's files in an interactive sftp shell",
+        allow_missing_positional = true,
+        disable_help_flag = true
+    )]
+    Sftp(ServerArgs),
+    #[command(
+        name = "edit-remote",
+        about = "Edit a file on a server in your local editor",
+        disable_help_flag = true
+    )]
+    EditRemote(EditRemoteArgs),
+    #[command(
+        name = "sync",
+        about = "Sync a local directory with a remote one over sftp",
+        disable_help_flag = true
+    )]
+    Sync(SyncArgs),
+    #[command(
+        name = "record",
+        about = "Connect to a server and record the session as an asciicast file",
+        allow_missing_positional = true,
+        disable_help_flag = true
+    )]
+    Record(RecordArgs),
+    #[command(
+        name = "play",
+        about = "Play back a recorded session",
+        disable_help_flag = true
+    )]
+    Play(PlayArgs),
+    #[command(
+        name = "undo",
+        about = "Restore a previous version of the config",
+        disable_help_flag = true
+    )]
+    Undo(UndoArgs),
+    #[command(
+        name = "history",
+        about = "Show the history of the config",
+        disable_help_flag = true
+    )]
+    History {
+        #[command(subcommand)]
+        command: HistorySubCommands,
+    },
+    #[cfg(unix)]
+    #[command(
+        name = "mux",
+        about = "Manage background connection masters",
+        disable_help_flag = true
+    )]
+    Mux {
+        #[command(subcommand)]
+        command: MuxSubCommands,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum HistorySubCommands {
+    #[command(name = "config", about = "List previous versions of the config")]
+    Config,
+}
+
+#[cfg(unix)]
+#[derive(Debug, Subcommand)]
+enum MuxSubCommands {
+    #[command(name = "status", about = "Show running masters")]
+    Status,
+    #[command(
+        name = "stop",
+        about = "Stop the masters of the given servers, or all of them",
+        allow_missing_positional = true
+    )]
+    Stop(MuxStopArgs),
+    #[command(name = "master", hide = true)]
+    Master,
 }
 
 #[derive(Debug, Args)]
@@ -100,10 +288,344 @@ struct ServerArgs {
     name: Option<String>,
 }
 
+#[derive(Debug, Args)]
+struct AddArgs {
+    #[arg(long, help = "Server name, defaults to the host")]
+    name: Option<String>,
+
+    #[arg(long, value_hint = ValueHint::Hostname)]
+    host: Option<String>,
+
+    #[arg(long, help = "Defaults to 22")]
+    port: Option<u16>,
+
+    #[arg(long, help = "Defaults to root")]
+    user: Option<String>,
+
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    identity_file: Option<String>,
+
+    #[arg(long, help = "Read the password from the first line of stdin")]
+    password_stdin: bool,
+
+    #[arg(
+        long = "tag",
+        value_name = "TAG",
+        help = "Can be repeated or comma separated",
+        value_delimiter = ','
+    )]
+    tags: Vec<String>,
+
+    #[arg(long, value_name = "GROUP")]
+    group: Option<String>,
+}
+
+impl AddArgs {
+    /// Without any flags the form asks for everything.
+    fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.host.is_none()
+            && self.port.is_none()
+            && self.user.is_none()
+            && self.identity_file.is_none()
+            && !self.password_stdin
+            && self.tags.is_empty()
+            && self.group.is_none()
+    }
+}
+
+#[derive(Debug, Args)]
+struct EditArgs {
+    #[arg(add = ArgValueCompleter::new(server_completer), num_args = ..=1)]
+    name: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "KEY=VALUE",
+        help = "Change a setting instead of using the form e.g. --set port=2222, can be repeated",
+        value_parser = parse_key_val
+    )]
+    set: Vec<(String, String)>,
+
+    #[arg(
+        long,
+        value_name = "KEY",
+        help = "Clear a setting instead of using the form, can be repeated"
+    )]
+    unset: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+struct ShowArgs {
+    #[arg(add = ArgValueCompleter::new(server_completer), num_args = ..=1)]
+    name: Option<String>,
+
+    #[arg(long, help = "Include inherited settings and where each one came from")]
+    resolved: bool,
+}
+
 #[derive(Debug, Args)]
 struct ServersArgs {
-    #[arg(add = ArgValueCompleter::new(server_completer), num_args = ..=servers_len())]
+    #[arg(add = ArgValueCompleter::new(server_completer), num_args = ..=servers_len().max(1))]
+    names: Vec<String>,
+
+    #[command(flatten)]
+    filter: FilterArgs,
+}
+
+#[derive(Debug, Args)]
+struct FilterArgs {
+    #[arg(
+        long = "tag",
+        value_name = "TAG",
+        help = "Only servers with this tag, may be repeated or comma separated, * and ? are wildcards",
+        value_delimiter = ',',
+        add = ArgValueCompleter::new(tag_completer)
+    )]
+    tags: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "GROUP",
+        help = "Only servers in this group, * and ? are wildcards",
+        add = ArgValueCompleter::new(group_completer)
+    )]
+    group: Option<String>,
+}
+
+impl From<&FilterArgs> for ServerFilter {
+    fn from(args: &FilterArgs) -> Self {
+        ServerFilter {
+            tags: args.tags.clone(),
+            group: args.group.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct ClusterArgs {
+    #[arg(
+        help = "Server names, * and ? match several servers",
+        add = ArgValueCompleter::new(server_completer)
+    )]
     names: Vec<String>,
+
+    #[command(flatten)]
+    filter: FilterArgs,
+}
+
+#[derive(Debug, Args)]
+struct ExecArgs {
+    #[arg(
+        short,
+        long,
+        value_name = "SERVERS",
+        help = "Comma separated server names, * and ? match several servers",
+        value_delimiter = ',',
+        add = ArgValueCompleter::new(server_completer)
+    )]
+    targets: Vec<String>,
+
+    #[command(flatten)]
+    filter: FilterArgs,
+
+    #[arg(
+        short,
+        long,
+        help = "Run on at most this many servers at a time",
+        default_value_t = 10
+    )]
+    jobs: usize,
+
+    #[arg(
+        short,
+        long,
+        help = "Print each server's output at the end instead of streaming it"
+    )]
+    grouped: bool,
+
+    #[arg(last = true, required = true, help = "Command to run")]
+    command: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+struct TransferArgs {
+    #[arg(short, long, help = "Copy directories recursively")]
+    recursive: bool,
+
+    #[arg(short, long, help = "Preserve permissions and modification times")]
+    preserve: bool,
+
+    #[arg(
+        short = 'c',
+        long = "continue",
+        help = "Resume partially transferred files"
+    )]
+    resume: bool,
+}
+
+impl From<&TransferArgs> for TransferOptions {
+    fn from(args: &TransferArgs) -> Self {
+        Self {
+            recursive: args.recursive,
+            preserve: args.preserve,
+            resume: args.resume,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct GetArgs {
+    #[arg(value_name = "SERVER:PATH", help = "Remote file or directory")]
+    source: String,
+
+    #[arg(
+        help = "Local destination, default is the current directory",
+        default_value = ".",
+        value_hint = ValueHint::AnyPath
+    )]
+    target: String,
+
+    #[command(flatten)]
+    transfer: TransferArgs,
+}
+
+#[derive(Debug, Args)]
+struct PutArgs {
+    #[arg(help = "Local file or directory", value_hint = ValueHint::AnyPath)]
+    source: String,
+
+    #[arg(value_name = "SERVER:PATH", help = "Remote destination")]
+    target: String,
+
+    #[command(flatten)]
+    transfer: TransferArgs,
+}
+
+#[derive(Debug, Args)]
+struct UndoArgs {
+    #[arg(
+        value_name = "N",
+        help = "Version to restore, as numbered by `ssher history config`",
+        default_value_t = 1
+    )]
+    version: usize,
+
+    #[arg(short, long, help = "Restore without asking")]
+    yes: bool,
+}
+
+#[derive(Debug, Args)]
+struct EditRemoteArgs {
+    #[arg(value_name = "SERVER:PATH", help = "Remote file to edit")]
+    target: String,
+}
+
+#[derive(Debug, Args)]
+struct SyncArgs {
+    #[arg(
+        value_name = "SOURCE",
+        help = "Directory to copy from, local or <server>:<path>"
+    )]
+    source: String,
+
+    #[arg(
+        value_name = "TARGET",
+        help = "Directory to bring up to date, local or <server>:<path>"
+    )]
+    target: String,
+
+    #[arg(long, help = "Delete files in the target that aren't in the source")]
+    delete: bool,
+
+    #[arg(short = 'n', long, help = "Show what would change without changing it")]
+    dry_run: bool,
+
+    #[arg(
+        short,
+        long,
+        help = "Compare files by sha256 instead of modification time"
+    )]
+    checksum: bool,
+
+    #[arg(long, value_name = "GLOB", help = "Only sync files matching the glob")]
+    include: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "Skip files and directories matching the glob"
+    )]
+    exclude: Vec<String>,
+}
+
+impl From<&SyncArgs> for SyncOptions {
+    fn from(args: &SyncArgs) -> Self {
+        Self {
+            delete: args.delete,
+            dry_run: args.dry_run,
+            checksum: args.checksum,
+            include: args.include.clone(),
+            exclude: args.exclude.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct RecordArgs {
+    #[arg(add = ArgValueCompleter::new(server_completer), num_args = ..=1)]
+    name: Option<String>,
+
+    #[arg(
+        short,
+        long,
+        value_name = "PATH",
+        help = "Recording file, %n, %d and %t expand to the server name, date and time",
+        value_hint = ValueHint::FilePath,
+    )]
+    file: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct PlayArgs {
+    #[arg(value_hint = ValueHint::FilePath)]
+    file: String,
+
+    #[arg(short, long, help = "Playback speed", default_value_t = 1.0)]
+    speed: f64,
+
+    #[arg(
+        short,
+        long,
+        value_name = "SECONDS",
+        help = "Shorten pauses longer than this"
+    )]
+    idle_time_limit: Option<f64>,
+}
+
+#[cfg(unix)]
+#[derive(Debug, Args)]
+struct MuxStopArgs {
+    #[arg(
+        value_name = "TARGET",
+        help = "Server names or destinations e.g. user@host:port",
+        add = ArgValueCompleter::new(server_completer)
+    )]
+    names: Vec<String>,
+
+    #[arg(short = 'l', long, help = "User the master was started with")]
+    user: Option<String>,
+
+    #[arg(short, long, help = "Port the master was started with")]
+    port: Option<u16>,
+
+    #[arg(
+        short = 'o',
+        value_name = "KEY=VALUE",
+        help = "Setting overridden when the master was started, can be repeated",
+        value_parser = parse_key_val,
+    )]
+    options: Vec<(String, String)>,
 }
 
 #[derive(Debug, Args)]
@@ -134,13 +656,46 @@ enum CompletionSubCommands {
     Elvish,
 }
 
+impl Default for Cli {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Cli {
     pub fn new() -> Self {
         CompleteEnv::with_factory(Cli::command).complete();
-        Self::parse()
+
+        // A leading `--config` is taken out before parsing: building the
+        // command already reads the number of servers from the config, and
+        // as an argument it would turn the subcommand after it into a
+        // server name
+        let mut args: Vec<OsString> = std::env::args_os().collect();
+        let config = match args.get(1).and_then(|arg| arg.to_str()) {
+            Some("--config") if args.len() > 2 => {
+                let value = args.remove(2);
+                args.remove(1);
+                Some(value.to_string_lossy().into_owned())
+            }
+            Some(arg) if arg.starts_with("--config=") => {
+                let value = arg["--config=".len()..].to_string();
+                args.remove(1);
+                Some(value)
+            }
+            _ => None,
+        };
+        if let Some(path) = &config {
+            set_config_path(path);
+        }
+
+        Self::parse_from(args)
     }
 
     pub async fn run(&self) -> anyhow::Result<()> {
+        if let Some(path) = &self.config {
+            set_config_path(path);
+        }
+
         match &self.command {
             Some(SubCommands::Version) => {
                 version();
@@ -158,31 +713,126 @@ impl Cli {
                 };
                 print_completions(shell, &mut Cli::command())?;
             }
-            Some(SubCommands::Add) => {
+            Some(SubCommands::Add(args)) if args.is_empty() => {
                 add_server()?;
             }
-            Some(SubCommands::List) => {
-                list_servers()?;
+            Some(SubCommands::Add(args)) => {
+                add_server_with(NewServer {
+                    name: args.name.clone(),
+                    host: args.host.clone(),
+                    port: args.port,
+                    user: args.user.clone(),
+                    identity_file: args.identity_file.clone(),
+                    password_stdin: args.password_stdin,
+                    tags: args.tags.clone(),
+                    group: args.group.clone(),
+                })?;
+            }
+            Some(SubCommands::List(args)) => {
+                list_servers(ServerFilter::from(args))?;
             }
             Some(SubCommands::Edit(args)) => {
                 let server = args.name.clone().unwrap_or_default();
-                edit_server(server)?;
+                edit_server(server, args.set.clone(), args.unset.clone())?;
             }
             Some(SubCommands::Remove(args)) => {
-                remove_server(args.names.clone())?;
+                remove_server(args.names.clone(), ServerFilter::from(&args.filter))?;
             }
             Some(SubCommands::Rename(args)) => {
                 let server = args.name.clone().unwrap_or_default();
                 rename_server(server)?;
             }
+            Some(SubCommands::Show(args)) => {
+                let server = args.name.clone().unwrap_or_default();
+                show_server(server, args.resolved)?;
+            }
             Some(SubCommands::Import(args)) => {
                 let raw_ssh_config = args.config.clone().unwrap_or("~/.ssh/config".to_string());
                 let ssh_config = shellexpand::tilde(raw_ssh_config.as_str()).into_owned();
                 import_servers(ssh_config)?;
             }
+            Some(SubCommands::Cluster(args)) => {
+                cluster_servers(args.names.clone(), ServerFilter::from(&args.filter)).await?;
+            }
+            Some(SubCommands::Exec(args)) => {
+                let command = args.command.join(" ");
+                exec_servers(
+                    args.targets.clone(),
+                    ServerFilter::from(&args.filter),
+                    command,
+                    args.jobs,
+                    args.grouped,
+                )
+                .await?;
+            }
+            Some(SubCommands::Get(args)) => {
+                let options = TransferOptions::from(&args.transfer);
+                get_files(args.source.clone(), args.target.clone(), options).await?;
+            }
+            Some(SubCommands::Put(args)) => {
+                let options = TransferOptions::from(&args.transfer);
+                put_files(args.source.clone(), args.target.clone(), options).await?;
+            }
+            Some(SubCommands::Sftp(args)) => {
+                let server = args.name.clone().unwrap_or_default();
+                sftp_shell(server).await?;
+            }
+            Some(SubCommands::Undo(args)) => {
+                undo_config(args.version, args.yes)?;
+            }
+            Some(SubCommands::History { command }) => match command {
+                HistorySubCommands::Config => config_history()?,
+            },
+            Some(SubCommands::EditRemote(args)) => {
+                edit_remote_file(args.target.clone()).await?;
+            }
+            Some(SubCommands::Sync(args)) => {
+                let options = SyncOptions::from(args);
+                sync_dirs(args.source.clone(), args.target.clone(), options).await?;
+            }
+            Some(SubCommands::Record(args)) => {
+                let server = args.name.clone().unwrap_or_default();
+                let overrides = Overrides {
+                    record: true,
+                    record_file: args.file.clone(),
+                    ..Default::default()
+                };
+                connect_server(server, None, overrides).await?;
+            }
+            Some(SubCommands::Play(args)) => {
+                let file = shellexpand::tilde(args.file.as_str()).into_owned();
+                play_recording(file, args.speed, args.idle_time_limit).await?;
+            }
+            #[cfg(unix)]
+            Some(SubCommands::Mux { command }) => match command {
+                MuxSubCommands::Status => mux_status().await?,
+                MuxSubCommands::Stop(args) => {
+                    let overrides = Overrides {
+                        user: args.user.clone(),
+                        port: args.port,
+                        options: args.options.clone(),
+                        ..Default::default()
+                    };
+                    mux_stop(args.names.clone(), overrides).await?
+                }
+                MuxSubCommands::Master => mux_master().await?,
+            },
             None => {
                 let server = self.server.clone().unwrap_or_default();
-                connect_server(server).await?;
+                let overrides = Overrides {
+                    user: self.user.clone(),
+                    port: self.port,
+                    identity_file: self.identity.clone(),
+                    log_file: self.log.clone(),
+                    record: false,
+                    record_file: None,
+                    forward_agent: self.forward_agent,
+                    forward_x11: self.forward_x11,
+                    forward_x11_trusted: self.forward_x11_trusted,
+                    env: self.env.clone(),
+                    options: self.options.clone(),
+                };
+                connect_server(server, self.target.clone(), overrides).await?;
             }
         }
 
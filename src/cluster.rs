@@ -0,0 +1,322 @@
+use crate::{
+    colord_print::{HOST_COLORS, green, yellow},
+    model::Server,
+    ssh::{RawModeGuard, RemoteChannel, Session, StdinReader, shell_request},
+};
+use console::{Color, style};
+use crossterm::terminal::size;
+use russh::ChannelMsg;
+use std::io::Write;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+/// Ctrl-], followed by a command key.
+const ESCAPE: u8 = 0x1d;
+
+const HELP: &str = "Ctrl-] then: 1-9 toggle input to a host, a enable all, n disable all, \
+l list hosts, q quit, Ctrl-] send Ctrl-]";
+
+enum Input {
+    Data(Vec<u8>),
+    Resize(u16, u16),
+    Eof,
+}
+
+enum Output {
+    Data(usize, Vec<u8>),
+    Closed(usize),
+}
+
+struct Host {
+    name: String,
+    color: Color,
+    input: UnboundedSender<Input>,
+    enabled: bool,
+    closed: bool,
+    /// Whether the next output from this host starts a new line.
+    line_start: bool,
+}
+
+/// Opens a shell on every server and broadcasts the keyboard to all of
+/// them, printing their output as interleaved lines prefixed by name.
+pub(crate) async fn run(servers: Vec<Server>) -> anyhow::Result<()> {
+    let width = servers.iter().map(|s| s.name.len()).max().unwrap_or(0);
+    let (col, row) = size()?;
+    let pty_col = col.saturating_sub(width as u16 + 3).max(20);
+
+    let connecting: Vec<_> = servers
+        .into_iter()
+        .map(|server| {
+            tokio::spawn(async move {
+                let name = server.name.clone();
+                let result = async {
+                    let request = shell_request(&server, pty_col, row);
+                    let session = Session::connect(server).await?;
+                    let channel = session.open_channel(&request).await?;
+                    anyhow::Ok((session, channel))
+                }
+                .await;
+                (name, result)
+            })
+        })
+        .collect();
+
+    let (output_tx, mut output_rx) = unbounded_channel();
+    let mut hosts = vec![];
+    for task in connecting {
+        let (name, result) = task.await?;
+        match result {
+            Ok((session, channel)) => {
+                let (input_tx, input_rx) = unbounded_channel();
+                tokio::spawn(relay(
+                    hosts.len(),
+                    session,
+                    channel,
+                    input_rx,
+                    output_tx.clone(),
+                ));
+                hosts.push(Host {
+                    name,
+                    color: HOST_COLORS[hosts.len() % HOST_COLORS.len()],
+                    input: input_tx,
+                    enabled: true,
+                    closed: false,
+                    line_start: true,
+                });
+            }
+            Err(e) => yellow(format!("😿 Failed to connect to <{}>: {}", name, e)),
+        }
+    }
+    drop(output_tx);
+
+    if hosts.is_empty() {
+        anyhow::bail!("😿 No servers connected.")
+    }
+    green(format!(
+        "😺 Broadcasting to {} servers. {}",
+        hosts.len(),
+        HELP
+    ));
+
+    let raw_mode = RawModeGuard::new()?;
+    let mut stdin = StdinReader::spawn();
+    let mut screen = Screen { width, last: None };
+    let mut term_size = (col, row);
+    let mut escaped = false;
+
+    loop {
+        let (col, row) = size()?;
+        if term_size != (col, row) {
+            term_size = (col, row);
+            let pty_col = col.saturating_sub(width as u16 + 3).max(20);
+            broadcast(&hosts, |_| true, || Input::Resize(pty_col, row));
+        }
+
+        tokio::select! {
+            r = stdin.read() => {
+                let data = r?;
+                if data.is_empty() {
+                    broadcast(&hosts, |_| true, || Input::Eof);
+                    break;
+                }
+
+                let mut pending = vec![];
+                for byte in data {
+                    if !escaped {
+                        if byte == ESCAPE {
+                            escaped = true;
+                        } else {
+                            pending.push(byte);
+                        }
+                        continue;
+                    }
+
+                    escaped = false;
+                    match byte {
+                        ESCAPE => pending.push(ESCAPE),
+                        b'1'..=b'9' => {
+                            flush(&hosts, &mut pending);
+                            let index = (byte - b'1') as usize;
+                            match hosts.get_mut(index) {
+                                Some(host) => {
+                                    host.enabled = !host.enabled;
+                                    let state = if host.enabled { "on" } else { "off" };
+                                    let message = format!("input to <{}> {}", host.name, state);
+                                    screen.notice(&message);
+                                }
+                                None => screen.notice(&format!("no host {}", index + 1)),
+                            }
+                        }
+                        b'a' | b'n' => {
+                            flush(&hosts, &mut pending);
+                            let enabled = byte == b'a';
+                            for host in hosts.iter_mut() {
+                                host.enabled = enabled;
+                            }
+                            let state = if enabled { "on" } else { "off" };
+                            screen.notice(&format!("input to all hosts {}", state));
+                        }
+                        b'l' => screen.list(&hosts),
+                        b'q' => {
+                            broadcast(&hosts, |_| true, || Input::Eof);
+                            pending.clear();
+                            hosts.iter_mut().for_each(|host| host.closed = true);
+                            break;
+                        }
+                        _ => screen.notice(HELP),
+                    }
+                }
+                flush(&hosts, &mut pending);
+
+                if hosts.iter().all(|host| host.closed) {
+                    break;
+                }
+            },
+            msg = output_rx.recv() => {
+                match msg {
+                    Some(Output::Data(index, data)) => screen.write(&mut hosts, index, &data)?,
+                    Some(Output::Closed(index)) => {
+                        hosts[index].closed = true;
+                        let message = format!("<{}> closed", hosts[index].name);
+                        screen.notice(&message);
+                    }
+                    None => break,
+                }
+                if hosts.iter().all(|host| host.closed) {
+                    break;
+                }
+            },
+        }
+    }
+
+    drop(raw_mode);
+    drop(hosts);
+    // Let the relays close their connections
+    while output_rx.recv().await.is_some() {}
+    println!();
+
+    Ok(())
+}
+
+fn broadcast(hosts: &[Host], filter: impl Fn(&Host) -> bool, input: impl Fn() -> Input) {
+    for host in hosts.iter().filter(|host| !host.closed && filter(host)) {
+        let _ = host.input.send(input());
+    }
+}
+
+fn flush(hosts: &[Host], pending: &mut Vec<u8>) {
+    if !pending.is_empty() {
+        broadcast(hosts, |host| host.enabled, || Input::Data(pending.clone()));
+        pending.clear();
+    }
+}
+
+/// Runs one server's channel, feeding it input and passing its output on.
+async fn relay(
+    index: usize,
+    session: Session,
+    mut channel: RemoteChannel,
+    mut input: UnboundedReceiver<Input>,
+    output: UnboundedSender<Output>,
+) {
+    loop {
+        tokio::select! {
+            msg = input.recv() => {
+                let result = match msg {
+                    Some(Input::Data(data)) => channel.data(&data).await,
+                    Some(Input::Resize(col, row)) => {
+                        channel.window_change(col as u32, row as u32).await
+                    }
+                    Some(Input::Eof) | None => {
+                        let _ = channel.eof().await;
+                        break;
+                    }
+                };
+                if result.is_err() {
+                    break;
+                }
+            },
+            msg = channel.wait() => {
+                match msg {
+                    Some(ChannelMsg::Data { ref data }) => {
+                        let _ = output.send(Output::Data(index, data.to_vec()));
+                    }
+                    Some(ChannelMsg::ExitStatus { .. }) | None => break,
+                    _ => {}
+                }
+            },
+        }
+    }
+
+    let _ = session.close().await;
+    let _ = output.send(Output::Closed(index));
+}
+
+/// Interleaves the hosts' output line by line on the local terminal.
+struct Screen {
+    width: usize,
+    /// The host whose line was written last, if it is still unfinished.
+    last: Option<usize>,
+}
+
+impl Screen {
+    fn write(&mut self, hosts: &mut [Host], index: usize, data: &[u8]) -> anyhow::Result<()> {
+        let mut stdout = std::io::stdout();
+
+        // Another host is mid-line, move ours to a fresh one
+        if self.last.is_some_and(|last| last != index) {
+            stdout.write_all(b"\r\n")?;
+        }
+        if self.last != Some(index) {
+            hosts[index].line_start = true;
+        }
+
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            if hosts[index].line_start {
+                let prefix = format!("{:<width$} | ", hosts[index].name, width = self.width);
+                write!(stdout, "{}", style(prefix).fg(hosts[index].color))?;
+            }
+            let (text, newline) = match line.strip_suffix(b"\n") {
+                Some(text) => (text.strip_suffix(b"\r").unwrap_or(text), true),
+                None => (line, false),
+            };
+            stdout.write_all(text)?;
+            if newline {
+                stdout.write_all(b"\r\n")?;
+            }
+            hosts[index].line_start = newline;
+        }
+        stdout.flush()?;
+
+        self.last = if hosts[index].line_start {
+            None
+        } else {
+            Some(index)
+        };
+        Ok(())
+    }
+
+    fn notice(&mut self, message: &str) {
+        let lead = if self.last.take().is_some() {
+            "\r\n"
+        } else {
+            ""
+        };
+        print!(
+            "{}{}\r\n",
+            lead,
+            style(format!("[ssher] {}", message)).dim()
+        );
+        let _ = std::io::stdout().flush();
+    }
+
+    fn list(&mut self, hosts: &[Host]) {
+        for (i, host) in hosts.iter().enumerate() {
+            let state = match (host.closed, host.enabled) {
+                (true, _) => "closed",
+                (false, true) => "on",
+                (false, false) => "off",
+            };
+            self.notice(&format!("{} {} {}", i + 1, host.name, state));
+        }
+    }
+}
@@ -1,16 +1,32 @@
+#[cfg(unix)]
+use crate::mux;
 use crate::{
-    colord_print::{green, yellow},
-    config::{load_config, save_config},
-    model::{Config, Server},
+    backup::{HistoryEntry, ServerChange, changed_sections, diff_servers, list_backups},
+    cluster,
+    colord_print::{green, red, yellow},
+    common::wildcard_match,
+    config::{load_config, lock_config, resolved_config_path, save_config, try_lock_config},
+    endec,
+    inherit::{Setting, rename_parent, server_settings},
+    model::{Config, Server, ServerFilter, parse_destination},
+    parallel,
     prompt::{
-        add_server_form_prompt, edit_server_form_prompt, rename_server_prompt,
+        ServerChoice, add_server_form_prompt, edit_server_form_prompt, input_prompt,
+        rename_server_prompt, server_name_prompt, servers_multi_select_prompt,
         servers_select_prompt, yesno_select_prompt,
     },
-    ssh,
+    record, remote_edit,
+    sftp::{self, Sftp, TransferOptions},
+    sftp_shell, ssh,
+    sync::{self, Direction, SyncOptions},
 };
 use anyhow::Ok;
-use std::vec;
-use tabled::{Table, settings::Style};
+use indicatif::HumanBytes;
+use std::{io::IsTerminal, path::Path, vec};
+use tabled::{
+    Table,
+    settings::{Remove, Style, location::ByColumnName},
+};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -18,14 +34,88 @@ pub(crate) fn version() {
     green(format!("😸 Version: v{}", VERSION));
 }
 
+/// Per-invocation settings patched onto the selected server, never saved.
+#[derive(Debug, Default)]
+pub(crate) struct Overrides {
+    pub(crate) user: Option<String>,
+    pub(crate) port: Option<u16>,
+    pub(crate) identity_file: Option<String>,
+    pub(crate) log_file: Option<String>,
+    pub(crate) record: bool,
+    pub(crate) record_file: Option<String>,
+    pub(crate) forward_agent: bool,
+    pub(crate) forward_x11: bool,
+    pub(crate) forward_x11_trusted: bool,
+    pub(crate) env: Vec<(String, String)>,
+    /// `-o key=value` pairs, applied before the dedicated flags.
+    pub(crate) options: Vec<(String, String)>,
+}
+
+impl Overrides {
+    fn apply(&self, server: &mut Server) -> anyhow::Result<()> {
+        for (key, value) in &self.options {
+            *server = server.with_secret_or_option(key, value)?;
+        }
+        if let Some(user) = &self.user {
+            server.user = user.clone();
+        }
+        if let Some(port) = self.port {
+            server.port = port;
+        }
+        if let Some(identity_file) = &self.identity_file {
+            server.identity_file = Some(identity_file.clone());
+            // An explicit key takes precedence over a stored password
+            server.password = None;
+        }
+        if let Some(log_file) = &self.log_file {
+            server.log_file = Some(log_file.clone());
+        }
+        if self.record {
+            server.record = Some(true);
+        }
+        if let Some(record_file) = &self.record_file {
+            server.record = Some(true);
+            server.record_file = Some(record_file.clone());
+        }
+        if self.forward_agent {
+            server.forward_agent = Some(true);
+        }
+        if self.forward_x11 {
+            server.forward_x11 = Some(true);
+        }
+        if self.forward_x11_trusted {
+            server.forward_x11_trusted = Some(true);
+        }
+        server.set_env.extend(self.env.clone());
+
+        Ok(())
+    }
+}
+
 fn get_server_from(config: &Config, name: &str) -> Option<Server> {
     config.servers.iter().find(|s| s.name == name).cloned()
 }
 
+fn read_only_message(server: &Server) -> String {
+    format!(
+        "😿 Server <{}> comes from {}, which is read-only.",
+        server.name,
+        server.source.as_deref().unwrap_or(Path::new("")).display()
+    )
+}
+
+fn ensure_writable(server: &Server) -> anyhow::Result<()> {
+    if server.read_only() {
+        anyhow::bail!(read_only_message(server))
+    }
+    Ok(())
+}
+
 pub(crate) fn import_servers(config: String) -> anyhow::Result<()> {
     let servers = sshconfig::parse_ssh_config(config.as_str())?;
 
     if !servers.is_empty() {
+        let _lock = lock_config()?;
         let mut config = load_config()?;
 
         let mut imported = 0;
@@ -56,37 +146,66 @@ pub(crate) fn import_servers(config: String) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub(crate) fn list_servers() -> anyhow::Result<()> {
+pub(crate) fn list_servers(filter: ServerFilter) -> anyhow::Result<()> {
     let config = load_config()?;
 
-    if config.servers.is_empty() {
+    let mut servers: Vec<&Server> = config
+        .servers
+        .iter()
+        .filter(|s| filter.matches(s))
+        .collect();
+    servers.sort_by_key(|s| s.group.as_deref());
+
+    if servers.is_empty() {
         yellow("😿 No servers found.");
     } else {
-        let table = Table::new(&config.servers)
-            .with(Style::modern_rounded())
-            .to_string();
+        let included = servers.iter().any(|s| s.read_only());
+        let mut table = Table::new(servers);
+        table.with(Style::modern_rounded());
+        if !included {
+            table.with(Remove::column(ByColumnName::new("SOURCE")));
+        }
 
         println!("{table}")
     }
     Ok(())
 }
 
-pub(crate) fn remove_server(servers: Vec<String>) -> anyhow::Result<()> {
+pub(crate) fn remove_server(servers: Vec<String>, filter: ServerFilter) -> anyhow::Result<()> {
+    let _lock = lock_config()?;
     let mut config = load_config()?;
 
-    let servers = if servers.is_empty() {
-        match servers_select_prompt(&config.servers) {
-            Some(s) => vec![s.name],
+    let servers = if servers.is_empty() && filter.is_empty() {
+        match select_locked_server(&mut config) {
+            Some(s) => {
+                ensure_writable(&s)?;
+                vec![s.name]
+            }
             None => return Ok(()),
         }
+    } else if servers.is_empty() {
+        let mut matched = vec![];
+        for s in config.servers.iter().filter(|s| filter.matches(s)) {
+            if s.read_only() {
+                yellow(read_only_message(s));
+            } else {
+                matched.push(s.name.clone());
+            }
+        }
+        if matched.is_empty() {
+            yellow("😿 No servers found.");
+        }
+        matched
     } else {
         let mut servers_removed = vec![];
         for name in servers.clone() {
             // if !config.servers.iter().any(|s| s.name == name) {
-            if get_server_from(&config, &name).is_none() {
-                yellow(format!("😿 No server <{}> found.", &name));
-            } else {
-                servers_removed.push(name);
+            match get_server_from(&config, &name).filter(|s| filter.matches(s)) {
+                None => yellow(format!("😿 No server <{}> found.", &name)),
+                Some(s) => {
+                    ensure_writable(&s)?;
+                    servers_removed.push(name)
+                }
             }
         }
         servers_removed
@@ -113,13 +232,19 @@ pub(crate) fn remove_server(servers: Vec<String>) -> anyhow::Result<()> {
 }
 
 pub(crate) fn add_server() -> anyhow::Result<()> {
+    let _lock = lock_config()?;
     let mut config = load_config()?;
+    add_server_to(&mut config)
+}
 
-    if let Some(server) = add_server_form_prompt(&config)? {
+/// Adds a server through the form, the caller holds the lock `config` was
+/// loaded under.
+fn add_server_to(config: &mut Config) -> anyhow::Result<()> {
+    if let Some(server) = add_server_form_prompt(config)? {
         let server_name = server.name.clone();
 
         config.servers.push(server);
-        save_config(&config)?;
+        save_config(config)?;
 
         green(format!("😺 Server {} added.", server_name));
     }
@@ -127,44 +252,175 @@ pub(crate) fn add_server() -> anyhow::Result<()> {
     Ok(())
 }
 
-pub(crate) fn edit_server(server: String) -> anyhow::Result<()> {
+/// Asks which server to use. Picking "Add a new server" adds one with `add`
+/// instead, nothing is selected then.
+fn select_server_with(
+    servers: &[Server],
+    add: impl FnOnce() -> anyhow::Result<()>,
+) -> Option<Server> {
+    match servers_select_prompt(servers) {
+        ServerChoice::Server(server) => Some(*server),
+        ServerChoice::Add => {
+            if let Err(e) = add() {
+                yellow(format!("😾 {}", e));
+            }
+            None
+        }
+        ServerChoice::Exit => None,
+    }
+}
+
+/// Asks which server to use, for callers that don't hold the lock.
+fn select_server(config: &Config) -> Option<Server> {
+    select_server_with(&config.servers, add_server)
+}
+
+/// Asks which server to use, a new server is added to `config` under the
+/// lock the caller already holds, taking it again would deadlock.
+fn select_locked_server(config: &mut Config) -> Option<Server> {
+    let servers = config.servers.clone();
+    select_server_with(&servers, || add_server_to(config))
+}
+
+/// A server for `ssher add` given as flags instead of through the form.
+#[derive(Debug, Default)]
+pub(crate) struct NewServer {
+    /// Defaults to the host.
+    pub(crate) name: Option<String>,
+    pub(crate) host: Option<String>,
+    pub(crate) port: Option<u16>,
+    pub(crate) user: Option<String>,
+    pub(crate) identity_file: Option<String>,
+    /// Read the password from the first line of stdin.
+    pub(crate) password_stdin: bool,
+    pub(crate) tags: Vec<String>,
+    pub(crate) group: Option<String>,
+}
+
+/// Adds a server without the form, only asking for the host if it's
+/// missing and there's a terminal to ask on.
+pub(crate) fn add_server_with(new: NewServer) -> anyhow::Result<()> {
+    let password = if new.password_stdin {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        let password = line.trim_end_matches(['\r', '\n']).to_string();
+        if password.is_empty() {
+            anyhow::bail!("😿 No password found on stdin.")
+        }
+        password
+    } else {
+        String::new()
+    };
+
+    let _lock = lock_config()?;
+    let mut config = load_config()?;
+
+    let is_pattern = new.name.as_ref().is_some_and(|n| n.contains(['*', '?']));
+    let host = match new.host {
+        Some(host) => host,
+        None if is_pattern => String::new(),
+        None if !new.password_stdin && std::io::stdin().is_terminal() => input_prompt("Host(*):")?,
+        None => anyhow::bail!("😿 Pass --host, or run in a terminal to be asked for it."),
+    };
+    let name = new.name.unwrap_or_else(|| host.clone());
+    if config.servers.iter().any(|s| s.name == name) {
+        anyhow::bail!("😿 Server <{}> already exists.", name)
+    }
+
+    // Patterns leave what isn't given to the destination
+    let (port, user) = if is_pattern {
+        (new.port.unwrap_or_default(), new.user.unwrap_or_default())
+    } else {
+        (
+            new.port.unwrap_or(22),
+            new.user.unwrap_or("root".to_string()),
+        )
+    };
+    config.servers.push(Server {
+        name: name.clone(),
+        host,
+        port,
+        user,
+        password: endec::encode_string(password),
+        identity_file: new.identity_file,
+        tags: new.tags,
+        group: new.group,
+        ..Default::default()
+    });
+    save_config(&config)?;
+
+    green(format!("😺 Server {} added.", name));
+    Ok(())
+}
+
+/// Edits a server through the form, or with `key=value` settings and keys
+/// to clear when any are given.
+pub(crate) fn edit_server(
+    server: String,
+    set: Vec<(String, String)>,
+    unset: Vec<String>,
+) -> anyhow::Result<()> {
+    let _lock = lock_config()?;
     let mut config = load_config()?;
 
+    let interactive = set.is_empty() && unset.is_empty();
     let server = match get_server_from(&config, server.as_str()) {
         Some(s) => s.clone(),
+        None if !interactive && !std::io::stdin().is_terminal() => {
+            anyhow::bail!("😿 No server <{}> found.", server)
+        }
         None => {
-            if let Some(s) = servers_select_prompt(&config.servers) {
+            if let Some(s) = select_locked_server(&mut config) {
                 s
             } else {
                 return Ok(());
             }
         }
     };
+    ensure_writable(&server)?;
 
-    if let Some(new_server) = edit_server_form_prompt(&config, &server)? {
-        if let Some(index) = config.servers.iter().position(|s| s.name == server.name) {
-            config.servers[index] = new_server;
-            save_config(&config)?;
-            green(format!("😺 Server {} updated.", server.name));
-        };
+    let new_server = if interactive {
+        edit_server_form_prompt(&config, &server)?
+    } else {
+        let mut new_server = server.clone();
+        for (key, value) in &set {
+            new_server = new_server.with_secret_or_option(key, value)?;
+        }
+        for key in &unset {
+            new_server = new_server.without_option(key)?;
+        }
+        if let Some(field) = new_server.missing_field() {
+            anyhow::bail!("😿 Server {} needs a {}.", server.name, field)
+        }
+        Some(new_server)
+    };
+
+    if let Some(new_server) = new_server
+        && let Some(index) = config.servers.iter().position(|s| s.name == server.name)
+    {
+        config.servers[index] = new_server;
+        save_config(&config)?;
+        green(format!("😺 Server {} updated.", server.name));
     }
 
     Ok(())
 }
 
 pub(crate) fn rename_server(server: String) -> anyhow::Result<()> {
+    let _lock = lock_config()?;
     let mut config = load_config()?;
 
     let server = match get_server_from(&config, server.as_str()) {
         Some(s) => s.clone(),
         None => {
-            if let Some(s) = servers_select_prompt(&config.servers) {
+            if let Some(s) = select_locked_server(&mut config) {
                 s
             } else {
                 return Ok(());
             }
         }
     };
+    ensure_writable(&server)?;
 
     let new_name = rename_server_prompt(&config, &server)?;
     if server.name != new_name {
@@ -173,6 +429,7 @@ pub(crate) fn rename_server(server: String) -> anyhow::Result<()> {
                 s.name = new_name.clone();
             }
         }
+        rename_parent(&mut config, &server.name, &new_name);
         save_config(&config)?;
 
         green(format!(
@@ -184,13 +441,13 @@ pub(crate) fn rename_server(server: String) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub(crate) async fn connect_server(server: String) -> anyhow::Result<()> {
-    let mut config = load_config()?;
+pub(crate) fn show_server(server: String, resolved: bool) -> anyhow::Result<()> {
+    let config = load_config()?;
 
     let server = match get_server_from(&config, server.as_str()) {
-        Some(s) => s.clone(),
+        Some(s) => s,
         None => {
-            if let Some(s) = servers_select_prompt(&config.servers) {
+            if let Some(s) = select_server(&config) {
                 s
             } else {
                 return Ok(());
@@ -198,9 +455,506 @@ pub(crate) async fn connect_server(server: String) -> anyhow::Result<()> {
         }
     };
 
+    let settings: Vec<Setting> = server_settings(&server, resolved)?;
+    let mut table = Table::new(settings);
+    table.with(Style::modern_rounded());
+    if !resolved {
+        table.with(Remove::column(ByColumnName::new("SOURCE")));
+    }
+    println!("{table}");
+
+    Ok(())
+}
+
+pub(crate) fn config_history() -> anyhow::Result<()> {
+    let backups = list_backups(&resolved_config_path());
+    if backups.is_empty() {
+        yellow("😿 No previous versions of the config found.");
+        return Ok(());
+    }
+
+    let mut configs = vec![load_config()?];
+    for backup in &backups {
+        configs.push(backup.load()?);
+    }
+    let entries: Vec<HistoryEntry> = backups
+        .iter()
+        .enumerate()
+        .map(|(i, backup)| HistoryEntry {
+            index: i + 1,
+            saved: backup.time.format("%Y-%m-%d %H:%M:%S").to_string(),
+            servers: configs[i + 1]
+                .servers
+                .iter()
+                .filter(|s| !s.read_only())
+                .count(),
+            changes: diff_servers(&configs[i + 1], &configs[i])
+                .iter()
+                .map(|change| change.to_string())
+                .chain(
+                    changed_sections(&configs[i + 1], &configs[i])
+                        .into_iter()
+                        .map(|section| format!("~{}", section)),
+                )
+                .collect::<Vec<_>>()
+                .join(", "),
+        })
+        .collect();
+
+    let table = Table::new(&entries)
+        .with(Style::modern_rounded())
+        .to_string();
+    println!("{table}");
+    Ok(())
+}
+
+/// Restores the `version`th most recent backup. The config it replaces is
+/// backed up as well, so an undo can be undone.
+pub(crate) fn undo_config(version: usize, yes: bool) -> anyhow::Result<()> {
+    let _lock = lock_config()?;
+
+    let backups = list_backups(&resolved_config_path());
+    let Some(backup) = version.checked_sub(1).and_then(|i| backups.get(i)) else {
+        anyhow::bail!("😿 No version {} in the config history.", version)
+    };
+    let restored = backup.load()?;
+    let current = load_config()?;
+    let changes = diff_servers(&current, &restored);
+    let sections = changed_sections(&current, &restored);
+    if changes.is_empty() && sections.is_empty() {
+        yellow(format!(
+            "😿 Version {} is the same as the current config.",
+            version
+        ));
+        return Ok(());
+    }
+
+    println!(
+        "Restoring the config saved at {}:",
+        backup.time.format("%Y-%m-%d %H:%M:%S")
+    );
+    for change in &changes {
+        match change {
+            ServerChange::Added(name) => green(format!("  + {}", name)),
+            ServerChange::Removed(name) => red(format!("  - {}", name)),
+            ServerChange::Changed(name, fields) => {
+                yellow(format!("  ~ {} ({})", name, fields.join(", ")))
+            }
+        }
+    }
+    for section in sections {
+        yellow(format!("  ~ {}", section));
+    }
+
+    if !yes {
+        if !std::io::stdin().is_terminal() {
+            anyhow::bail!("😿 Pass --yes to restore without asking.")
+        }
+        if !yesno_select_prompt("Restore this version?")? {
+            return Ok(());
+        }
+    }
+    save_config(&restored)?;
+    green(format!("😺 Config restored to version {}.", version));
+
+    Ok(())
+}
+
+/// Looks up servers by name among those passing `filter`, names may
+/// contain `*` and `?` wildcards. Asks which servers to use when neither
+/// names nor a filter are given.
+fn resolve_servers(
+    config: &Config,
+    names: &[String],
+    filter: &ServerFilter,
+) -> anyhow::Result<Vec<Server>> {
+    let candidates: Vec<Server> = config
+        .servers
+        .iter()
+        .filter(|s| !s.is_pattern() && filter.matches(s))
+        .cloned()
+        .collect();
+    if names.is_empty() && filter.is_empty() {
+        return servers_multi_select_prompt(&candidates);
+    }
+    if names.is_empty() {
+        if candidates.is_empty() {
+            yellow("😿 No servers found.");
+        }
+        return Ok(candidates);
+    }
+
+    let mut servers: Vec<Server> = vec![];
+    for name in names {
+        let matched: Vec<&Server> = candidates
+            .iter()
+            .filter(|s| wildcard_match(name, &s.name))
+            .collect();
+        if matched.is_empty() {
+            yellow(format!("😿 No server <{}> found.", name));
+        }
+        for server in matched {
+            if !servers.iter().any(|s| s.name == server.name) {
+                servers.push(server.clone());
+            }
+        }
+    }
+    Ok(servers)
+}
+
+pub(crate) async fn cluster_servers(
+    names: Vec<String>,
+    filter: ServerFilter,
+) -> anyhow::Result<()> {
+    let config = load_config()?;
+
+    let servers = resolve_servers(&config, &names, &filter)?;
+    if servers.is_empty() {
+        return Ok(());
+    }
+
+    cluster::run(servers).await
+}
+
+pub(crate) async fn exec_servers(
+    names: Vec<String>,
+    filter: ServerFilter,
+    command: String,
+    jobs: usize,
+    group: bool,
+) -> anyhow::Result<()> {
+    let config = load_config()?;
+
+    let servers = resolve_servers(&config, &names, &filter)?;
+    if servers.is_empty() {
+        return Ok(());
+    }
+
+    let total = servers.len();
+    let results = parallel::run(servers, command, jobs, group).await?;
+    let table = Table::new(&results)
+        .with(Style::modern_rounded())
+        .to_string();
+    println!("{table}");
+
+    let failed = results.iter().filter(|r| !r.success()).count();
+    if failed > 0 {
+        anyhow::bail!("😿 Failed on {} of {} servers.", failed, total)
+    }
+    green(format!("😺 Succeeded on all {} servers.", total));
+
+    Ok(())
+}
+
+/// Splits `<server>:<path>`, anything with a `/` before the colon is a
+/// local path.
+fn split_remote(spec: &str) -> Option<(&str, &str)> {
+    let (server, path) = spec.split_once(':')?;
+    let drive_letter = cfg!(windows) && server.len() == 1;
+    if server.is_empty() || server.contains(['/', '\\']) || drive_letter {
+        return None;
+    }
+    Some((server, path))
+}
+
+/// A saved server, or a destination that isn't saved: `user@host`, or a
+/// host matching a pattern server.
+fn lookup_server(config: &Config, name: &str) -> anyhow::Result<Server> {
+    match get_server_from(config, name) {
+        Some(server) => ensure_connectable(server),
+        None if name.contains('@') || config.matching_patterns(name).next().is_some() => {
+            config.destination_server(name)
+        }
+        None => anyhow::bail!("😿 No server <{}> found.", name),
+    }
+}
+
+fn ensure_connectable(server: Server) -> anyhow::Result<Server> {
+    if server.is_pattern() {
+        anyhow::bail!(
+            "😿 Server <{}> is a pattern, connect to a host matching it instead.",
+            server.name
+        )
+    }
+    Ok(server)
+}
+
+pub(crate) async fn get_files(
+    source: String,
+    target: String,
+    options: TransferOptions,
+) -> anyhow::Result<()> {
+    let config = load_config()?;
+
+    let Some((name, remote)) = split_remote(&source) else {
+        anyhow::bail!("😿 Expected <server>:<path>, got {}.", source)
+    };
+    let server = lookup_server(&config, name)?;
+    let target = shellexpand::tilde(&target).into_owned();
+
+    let sftp = Sftp::connect(server).await?;
+    let result = sftp.download(remote, Path::new(&target), options).await;
+    sftp.close().await?;
+    let stats = result?;
+
+    green(format!(
+        "😺 Downloaded {} files, {}.",
+        stats.files,
+        HumanBytes(stats.bytes)
+    ));
+    Ok(())
+}
+
+pub(crate) async fn put_files(
+    source: String,
+    target: String,
+    options: TransferOptions,
+) -> anyhow::Result<()> {
+    let config = load_config()?;
+
+    let Some((name, remote)) = split_remote(&target) else {
+        anyhow::bail!("😿 Expected <server>:<path>, got {}.", target)
+    };
+    let server = lookup_server(&config, name)?;
+    let source = shellexpand::tilde(&source).into_owned();
+
+    let sftp = Sftp::connect(server).await?;
+    let result = sftp.upload(Path::new(&source), remote, options).await;
+    sftp.close().await?;
+    let stats = result?;
+
+    green(format!(
+        "😺 Uploaded {} files, {}.",
+        stats.files,
+        HumanBytes(stats.bytes)
+    ));
+    Ok(())
+}
+
+pub(crate) async fn edit_remote_file(target: String) -> anyhow::Result<()> {
+    let config = load_config()?;
+
+    let Some((name, remote)) = split_remote(&target) else {
+        anyhow::bail!("😿 Expected <server>:<path>, got {}.", target)
+    };
+    let server = lookup_server(&config, name)?;
+
+    let sftp = Sftp::connect(server).await?;
+    let result = remote_edit::run(&sftp, remote).await;
+    sftp.close().await?;
+
+    match result? {
+        remote_edit::Outcome::Unchanged => yellow("😺 No changes, nothing uploaded."),
+        remote_edit::Outcome::Uploaded(bytes) => {
+            green(format!("😺 Saved {}, {}.", target, HumanBytes(bytes)))
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn sync_dirs(
+    source: String,
+    target: String,
+    options: SyncOptions,
+) -> anyhow::Result<()> {
+    let config = load_config()?;
+
+    let (name, local, remote, direction) = match (split_remote(&source), split_remote(&target)) {
+        (None, Some((name, remote))) => (name, &source, remote, Direction::Upload),
+        (Some((name, remote)), None) => (name, &target, remote, Direction::Download),
+        _ => anyhow::bail!("😿 Exactly one of the directories must be <server>:<path>."),
+    };
+    let server = lookup_server(&config, name)?;
+    let local = shellexpand::tilde(local).into_owned();
+    let remote = sftp::remote_path(remote);
+
+    let sftp = Sftp::connect(server).await?;
+    let result = sync::sync(&sftp, Path::new(&local), &remote, direction, &options).await;
+    sftp.close().await?;
+    let summary = result?;
+
+    if summary.actions.is_empty() {
+        green(format!(
+            "😺 Already in sync, {} files unchanged.",
+            summary.unchanged
+        ));
+        return Ok(());
+    }
+
+    let table = Table::new(&summary.actions)
+        .with(Style::modern_rounded())
+        .to_string();
+    println!("{table}");
+
+    let count = |action: &str| {
+        summary
+            .actions
+            .iter()
+            .filter(|a| a.action == action)
+            .count()
+    };
+    let counts = format!(
+        "{} copied, {} updated, {} deleted, {} unchanged",
+        count("copy"),
+        count("update"),
+        count("delete"),
+        summary.unchanged
+    );
+    if options.dry_run {
+        yellow(format!("😺 Dry run, nothing changed: {}.", counts));
+    } else {
+        green(format!(
+            "😺 Synced {}, {}.",
+            counts,
+            HumanBytes(summary.bytes)
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) async fn sftp_shell(server: String) -> anyhow::Result<()> {
+    let config = load_config()?;
+
+    let server = if server.is_empty() {
+        match select_server(&config) {
+            Some(s) => s,
+            None => return Ok(()),
+        }
+    } else {
+        lookup_server(&config, &server)?
+    };
+    let name = server.name.clone();
+
+    let sftp = Sftp::connect(server).await?;
+    let result = sftp_shell::run(&sftp, &name).await;
+    sftp.close().await?;
+
+    result
+}
+
+pub(crate) async fn play_recording(
+    file: String,
+    speed: f64,
+    idle_time_limit: Option<f64>,
+) -> anyhow::Result<()> {
+    record::play(&file, speed, idle_time_limit).await
+}
+
+#[cfg(unix)]
+pub(crate) async fn mux_status() -> anyhow::Result<()> {
+    let masters = mux::masters().await?;
+
+    if masters.is_empty() {
+        yellow("😿 No mux masters running.");
+    } else {
+        let table = Table::new(masters.into_iter().map(|(_, status)| status))
+            .with(Style::modern_rounded())
+            .to_string();
+
+        println!("{table}")
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) async fn mux_stop(servers: Vec<String>, overrides: Overrides) -> anyhow::Result<()> {
+    let paths = if servers.is_empty() {
+        mux::masters()
+            .await?
+            .into_iter()
+            .map(|(path, status)| (status.name, path))
+            .collect()
+    } else {
+        // Found the way connecting does, so the master started then matches
+        let config = load_config()?;
+        let mut paths = vec![];
+        for name in servers {
+            let mut server = match get_server_from(&config, &name) {
+                Some(s) => ensure_connectable(s)?,
+                None => config.destination_server(&name)?,
+            };
+            overrides.apply(&mut server)?;
+            paths.push((name, mux::socket_path(&server)));
+        }
+        paths
+    };
+
+    for (name, path) in paths {
+        if mux::stop(&path).await.is_ok() {
+            green(format!("😺 Mux master for {} stopped.", name));
+        } else {
+            yellow(format!("😿 No mux master running for {}.", name));
+        }
+    }
+
+    Ok(())
+}
+
+/// Entry point of the background master spawned by `mux::connect`, the
+/// server to connect to is read from stdin.
+#[cfg(unix)]
+pub(crate) async fn mux_master() -> anyhow::Result<()> {
+    let mut content = String::new();
+    std::io::Read::read_to_string(&mut std::io::stdin(), &mut content)?;
+    let server: Server = serde_yaml::from_str(&content)?;
+
+    mux::serve(server).await
+}
+
+/// Connects to a saved server, `target` may also be a destination such as
+/// `user@host:port` which is connected to without being saved first.
+/// What's saved of a server connected to by `destination`: where it is and
+/// how to log in, leaving out one-off overrides and pattern settings. The
+/// user and port fall back to the ones that were used to log in.
+fn server_to_save(
+    destination: &str,
+    overrides: &Overrides,
+    connected: &Server,
+) -> anyhow::Result<Server> {
+    let (user, host, port) = parse_destination(destination)?;
+    let mut server = Server::new(host);
+    server.user = overrides
+        .user
+        .clone()
+        .or(user)
+        .unwrap_or_else(|| connected.user.clone());
+    server.port = overrides.port.or(port).unwrap_or(connected.port);
+    if let Some(identity_file) = &overrides.identity_file {
+        server.identity_file = Some(identity_file.clone());
+    }
+    Ok(server)
+}
+
+pub(crate) async fn connect_server(
+    server: String,
+    target: Option<String>,
+    overrides: Overrides,
+) -> anyhow::Result<()> {
+    let config = load_config()?;
+
+    let name = target.clone().unwrap_or(server);
+    let (mut server, transient) = match get_server_from(&config, name.as_str()) {
+        Some(s) => (ensure_connectable(s)?, false),
+        None => match &target {
+            Some(target) => (config.destination_server(target)?, true),
+            None => {
+                if let Some(s) = select_server(&config) {
+                    (ensure_connectable(s)?, false)
+                } else {
+                    return Ok(());
+                }
+            }
+        },
+    };
+
     // If the server is not marked as current, mark it as current,
-    // and unmark all others.
-    if !server.current.unwrap_or(false) {
+    // and unmark all others. Skipped when another invocation is changing
+    // the config, it's not worth waiting for.
+    if !transient
+        && !server.current.unwrap_or(false)
+        && let Some(_lock) = try_lock_config()?
+    {
+        let mut config = load_config()?;
         for s in &mut config.servers {
             if s.name == server.name {
                 s.current = Some(true);
@@ -211,7 +965,79 @@ pub(crate) async fn connect_server(server: String) -> anyhow::Result<()> {
         save_config(&config)?;
     }
 
-    ssh::exec(server).await?;
+    overrides.apply(&mut server)?;
+    let had_password = server.password.is_some();
+    let connected = ssh::exec(server).await?;
+
+    if let Some(target) = target.filter(|_| transient)
+        && std::io::stdin().is_terminal()
+        && yesno_select_prompt("Save this server?")?
+    {
+        // Reload, the config may have changed while the session was open
+        let _lock = lock_config()?;
+        let mut config = load_config()?;
+        let mut server = server_to_save(&target, &overrides, &connected)?;
+        if !had_password {
+            server.password = connected.password;
+        }
+        server.name = server_name_prompt(&config, &server)?;
+        let name = server.name.clone();
+
+        config.servers.push(server);
+        save_config(&config)?;
+
+        green(format!("😺 Server {} added.", name));
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_paths_split_at_the_colon() {
+        assert_eq!(split_remote("web1:/var/log"), Some(("web1", "/var/log")));
+        assert_eq!(
+            split_remote("deploy@web1:logs"),
+            Some(("deploy@web1", "logs"))
+        );
+        assert_eq!(split_remote("web1:"), Some(("web1", "")));
+    }
+
+    #[test]
+    fn local_paths_stay_local() {
+        assert_eq!(split_remote("./a:b"), None);
+        assert_eq!(split_remote("logs/app.log"), None);
+        assert_eq!(split_remote(":path"), None);
+    }
+
+    #[test]
+    fn saved_servers_leave_one_off_overrides_out() {
+        let overrides = Overrides {
+            port: Some(2222),
+            log_file: Some("/tmp/session.log".to_string()),
+            forward_agent: true,
+            env: vec![("A".to_string(), "b".to_string())],
+            options: vec![("remote_dir".to_string(), "/srv".to_string())],
+            ..Default::default()
+        };
+        let mut connected = Server::from_destination("10.0.0.1").unwrap();
+        overrides.apply(&mut connected).unwrap();
+        connected.user = "deploy".to_string();
+
+        let server = server_to_save("10.0.0.1", &overrides, &connected).unwrap();
+        assert_eq!(server.host, "10.0.0.1");
+        assert_eq!(server.user, "deploy");
+        assert_eq!(server.port, 2222);
+        assert_eq!(server.log_file, None);
+        assert_eq!(server.forward_agent, None);
+        assert_eq!(server.remote_dir, None);
+        assert!(server.set_env.is_empty());
+
+        let server = server_to_save("root@10.0.0.1:22", &overrides, &connected).unwrap();
+        assert_eq!(server.user, "root");
+        assert_eq!(server.port, 2222);
+    }
+}
@@ -1,5 +1,15 @@
 #![allow(dead_code)]
-use console::style;
+use console::{Color, style};
+
+/// Told apart by color when several servers print to the same terminal.
+pub(crate) const HOST_COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Green,
+    Color::Yellow,
+    Color::Magenta,
+    Color::Blue,
+    Color::Red,
+];
 
 pub fn green<T: AsRef<str>>(s: T) {
     println!("{}", style(s.as_ref()).green())
@@ -1,4 +1,4 @@
-use crate::config::load_config;
+use crate::{config::load_config, model::Server};
 use clap::{Command, builder::StyledStr};
 use clap_complete::{CompletionCandidate, Shell, env::Shells, generate};
 use std::io;
@@ -45,3 +45,111 @@ pub(crate) fn server_completer(current: &std::ffi::OsStr) -> Vec<CompletionCandi
             .collect()
     })
 }
+
+pub(crate) fn tag_completer(current: &std::ffi::OsStr) -> Vec<CompletionCandidate> {
+    load_config().map_or(vec![], |c| {
+        let tags = c.servers.iter().flat_map(|s| s.tags.iter().cloned());
+        distinct_candidates(tags, current)
+    })
+}
+
+pub(crate) fn group_completer(current: &std::ffi::OsStr) -> Vec<CompletionCandidate> {
+    load_config().map_or(vec![], |c| {
+        let groups = c.servers.iter().filter_map(|s| s.group.clone());
+        distinct_candidates(groups, current)
+    })
+}
+
+fn distinct_candidates(
+    values: impl Iterator<Item = String>,
+    current: &std::ffi::OsStr,
+) -> Vec<CompletionCandidate> {
+    let current = current.to_str().unwrap_or_default();
+    let mut values: Vec<String> = values.filter(|v| v.starts_with(current)).collect();
+    values.sort();
+    values.dedup();
+    values.into_iter().map(CompletionCandidate::new).collect()
+}
+
+/// Matches `text` against a pattern where `*` matches any run of characters
+/// and `?` any single one, as in ssh_config.
+pub(crate) fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bt)) => {
+                    p = bp + 1;
+                    t = bt + 1;
+                    backtrack = Some((bp, bt + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Expands `~` and replaces `%n` (server name), `%h` (host), `%d` (date),
+/// `%t` (time) and `%%` in a file name template.
+pub(crate) fn expand_path_template(template: &str, server: &Server) -> String {
+    let now = chrono::Local::now();
+    let mut path = String::new();
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            path.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => path.push_str(&server.name),
+            Some('h') => path.push_str(&server.host),
+            Some('d') => path.push_str(&now.format("%Y-%m-%d").to_string()),
+            Some('t') => path.push_str(&now.format("%H%M%S").to_string()),
+            Some('%') => path.push('%'),
+            Some(other) => {
+                path.push('%');
+                path.push(other);
+            }
+            None => path.push('%'),
+        }
+    }
+
+    shellexpand::tilde(&path).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_match() {
+        assert!(wildcard_match("*.prod", "db1.prod"));
+        assert!(wildcard_match("db?.prod", "db1.prod"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("a*b*c", "aXbYbZc"));
+        assert!(wildcard_match("web", "web"));
+    }
+
+    #[test]
+    fn wildcards_match_whole_text() {
+        assert!(!wildcard_match("*.prod", "db1.prod.example"));
+        assert!(!wildcard_match("db?.prod", "db10.prod"));
+        assert!(!wildcard_match("web", "web1"));
+        assert!(!wildcard_match("?", ""));
+    }
+}
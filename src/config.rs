@@ -1,22 +1,330 @@
-use crate::model::Config;
-use std::{fs, path::Path};
+use crate::{
+    backup::backup_config,
+    common::wildcard_match,
+    inherit::{resolve, unresolve},
+    migration::{CONFIG_VERSION, migrate},
+    model::{Config, Server},
+};
+use console::style;
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
 
-const CONFIG_PATH: &str = "~/.ssher.yaml";
+/// Where the config lived before ssher followed the XDG base directories.
+const LEGACY_CONFIG_PATH: &str = "~/.ssher.yaml";
 
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Uses `path` as the config file, takes precedence over everything else.
+pub(crate) fn set_config_path(path: &str) {
+    let _ = CONFIG_PATH.set(expand(path));
+}
+
+/// The `--config` flag, then `SSHER_CONFIG`, then
+/// `$XDG_CONFIG_HOME/ssher/config.yaml`.
+pub(crate) fn config_path() -> PathBuf {
+    if let Some(path) = CONFIG_PATH.get() {
+        return path.clone();
+    }
+    if let Some(path) = env::var("SSHER_CONFIG").ok().filter(|p| !p.is_empty()) {
+        return expand(&path);
+    }
+
+    // Relative values are invalid per the spec and must be ignored
+    let config_home = env::var("XDG_CONFIG_HOME")
+        .ok()
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .unwrap_or_else(|| expand("~/.config"));
+    let path = config_home.join("ssher").join("config.yaml");
+    migrate_legacy(&path);
+    path
+}
+
+/// The file a symlinked config points to, saves replace it rather than the
+/// link and its backups are kept next to it.
+pub(crate) fn resolved_config_path() -> PathBuf {
+    let path = config_path();
+    fs::canonicalize(&path).unwrap_or(path)
+}
+
+fn expand(path: &str) -> PathBuf {
+    PathBuf::from(shellexpand::tilde(path).into_owned())
+}
+
+/// Moves `~/.ssher.yaml` to `path` the first time it's looked up. Failures
+/// leave the legacy file in place, to be tried again next time.
+fn migrate_legacy(path: &Path) {
+    let legacy = expand(LEGACY_CONFIG_PATH);
+    if path.exists() || !legacy.is_file() {
+        return;
+    }
+
+    let moved = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| {
+            fs::rename(&legacy, path).or_else(|_| {
+                fs::copy(&legacy, path)?;
+                fs::remove_file(&legacy)
+            })
+        });
+    match moved {
+        Ok(()) => eprintln!(
+            "{}",
+            style(format!(
+                "😺 Moved {} to {}.",
+                legacy.display(),
+                path.display()
+            ))
+            .yellow()
+        ),
+        Err(e) => eprintln!(
+            "{}",
+            style(format!(
+                "😿 Failed to move {} to {}: {}",
+                legacy.display(),
+                path.display(),
+                e
+            ))
+            .red()
+        ),
+    }
+}
+
+/// An exclusive lock on the config, held from loading it to saving it so
+/// that concurrent invocations don't overwrite each other's changes.
+pub(crate) struct ConfigLock {
+    _file: fs::File,
+}
+
+/// Waits until no other invocation holds the lock.
+pub(crate) fn lock_config() -> anyhow::Result<ConfigLock> {
+    let file = open_lock_file()?;
+    file.lock()
+        .map_err(|e| anyhow::anyhow!("Failed to lock config: {}", e))?;
+    Ok(ConfigLock { _file: file })
+}
+
+/// Like `lock_config`, but gives up instead of waiting.
+pub(crate) fn try_lock_config() -> anyhow::Result<Option<ConfigLock>> {
+    let file = open_lock_file()?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(ConfigLock { _file: file })),
+        Err(fs::TryLockError::WouldBlock) => Ok(None),
+        Err(fs::TryLockError::Error(e)) => Err(anyhow::anyhow!("Failed to lock config: {}", e)),
+    }
+}
+
+/// A separate file, since the config itself is replaced on every save.
+fn open_lock_file() -> anyhow::Result<fs::File> {
+    let path = config_path();
+    create_parent(&path)?;
+    let mut lock_path = path.into_os_string();
+    lock_path.push(".lock");
+
+    let mut options = fs::OpenOptions::new();
+    options.create(true).truncate(false).write(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(&lock_path)
+        .map_err(|e| anyhow::anyhow!("Failed to lock config: {}", e))
+}
+
+fn create_parent(path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(|e| anyhow::anyhow!("Failed to save config: {}", e))?;
+    }
+    Ok(())
+}
+
+/// The config along with the servers of the files it includes. Servers of
+/// the config itself win over included ones with the same name, and later
+/// includes win over earlier ones. Servers of the config itself may extend
+/// included ones.
 pub(crate) fn load_config() -> anyhow::Result<Config> {
-    let path = shellexpand::tilde(CONFIG_PATH).into_owned();
-    if Path::new(&path).exists() {
-        let content = fs::read_to_string(&path)
-            .map_err(|e| anyhow::anyhow!("Failed to read config: {}", e))?;
-        serde_yaml::from_str(&content).map_err(|e| anyhow::anyhow!("Failed to parse config: {}", e))
-    } else {
-        Ok(Config { servers: vec![] })
+    let path = config_path();
+    if !path.exists() {
+        return Ok(Config {
+            version: CONFIG_VERSION,
+            include: vec![],
+            defaults: Default::default(),
+            groups: Default::default(),
+            servers: vec![],
+        });
+    }
+
+    warn_permissions(&path);
+    let content =
+        fs::read_to_string(&path).map_err(|e| anyhow::anyhow!("Failed to read config: {}", e))?;
+    let included = load_included(&path, &content)?;
+    let mut config = parse_config(&content, &included)
+        .map_err(|e| anyhow::anyhow!("😿 Can't use {}: {}", path.display(), e))?;
+    add_included(&mut config, included);
+    Ok(config)
+}
+
+/// Loads a previous version of the config from `content`, along with what
+/// the files it includes hold now.
+pub(crate) fn load_config_from(content: &str) -> anyhow::Result<Config> {
+    let included = load_included(&config_path(), content)?;
+    let mut config = parse_config(content, &included)?;
+    add_included(&mut config, included);
+    Ok(config)
+}
+
+/// The servers of the files the config at `path` includes, later files
+/// override earlier ones.
+fn load_included(path: &Path, content: &str) -> anyhow::Result<Vec<Server>> {
+    #[derive(serde::Deserialize)]
+    struct Include {
+        #[serde(default)]
+        include: Vec<String>,
+    }
+    let include = serde_yaml::from_str::<Include>(content)
+        .map(|i| i.include)
+        .unwrap_or_default();
+
+    let base = path.parent().unwrap_or(Path::new("."));
+    let mut included: Vec<Server> = vec![];
+    for pattern in &include {
+        for file in expand_include(base, pattern) {
+            let content = fs::read_to_string(&file)
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", file.display(), e))?;
+            let layer = parse_config(&content, &[])
+                .map_err(|e| anyhow::anyhow!("😿 Can't use {}: {}", file.display(), e))?;
+            for mut server in layer.servers {
+                server.source = Some(file.clone());
+                server.current = None;
+                included.retain(|s| s.name != server.name);
+                included.push(server);
+            }
+        }
+    }
+    Ok(included)
+}
+
+/// Adds the included servers the config doesn't have one of its own for.
+fn add_included(config: &mut Config, mut included: Vec<Server>) {
+    included.retain(|s| !config.servers.iter().any(|own| own.name == s.name));
+    config.servers.extend(included);
+}
+
+/// Files matching an `include` entry, relative ones are relative to the
+/// directory of the config. Wildcards are allowed in the file name.
+fn expand_include(base: &Path, pattern: &str) -> Vec<PathBuf> {
+    let path = base.join(expand(pattern));
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        if !path.is_file() {
+            // The config is loaded more than once per invocation
+            static WARNED: Mutex<Vec<PathBuf>> = Mutex::new(vec![]);
+            let mut warned = WARNED.lock().unwrap_or_else(|e| e.into_inner());
+            if !warned.contains(&path) {
+                eprintln!(
+                    "{}",
+                    style(format!(
+                        "😿 Included {} not found, skipping.",
+                        path.display()
+                    ))
+                    .yellow()
+                );
+                warned.push(path);
+            }
+            return vec![];
+        }
+        return vec![path];
+    }
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| wildcard_match(&name, &entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    files.sort();
+    files
+}
+
+/// Parses a config, its servers may extend the `included` ones.
+pub(crate) fn parse_config(content: &str, included: &[Server]) -> anyhow::Result<Config> {
+    let mut value: serde_yaml::Value = serde_yaml::from_str(content)?;
+    migrate(&mut value)?;
+    let inherited = resolve(&mut value, included)?;
+    let mut config: Config = serde_yaml::from_value(value)?;
+    for (server, inherited) in config.servers.iter_mut().zip(inherited) {
+        server.inherited = inherited;
+    }
+
+    for server in &config.servers {
+        if let Some(field) = server.missing_field() {
+            anyhow::bail!("server {} has no {}", server.name, field)
+        }
     }
+    Ok(config)
 }
 
+/// The config holds passwords, so others shouldn't be able to read it.
+#[cfg(unix)]
+fn warn_permissions(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    static WARNED: std::sync::Once = std::sync::Once::new();
+
+    let mode = fs::metadata(path).map_or(0, |m| m.permissions().mode());
+    if mode & 0o077 != 0 {
+        WARNED.call_once(|| {
+            eprintln!(
+                "{}",
+                style(format!(
+                    "😿 {} is readable by other users, run `chmod 600 {}` to fix it.",
+                    path.display(),
+                    path.display()
+                ))
+                .yellow()
+            )
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_permissions(_path: &Path) {}
+
+/// Writes to a temporary file next to the config and renames it into place,
+/// so a crash never leaves a truncated config behind.
+/// Servers from included files are left out, they're read-only.
 pub(crate) fn save_config(config: &Config) -> anyhow::Result<()> {
-    let path = shellexpand::tilde(CONFIG_PATH).into_owned();
-    let content = serde_yaml::to_string(config)
+    let path = resolved_config_path();
+    let content = unresolve(config)
+        .and_then(|value| Ok(serde_yaml::to_string(&value)?))
         .map_err(|e| anyhow::anyhow!("Failed to serialize config: {}", e))?;
-    fs::write(path, content).map_err(|e| anyhow::anyhow!("Failed to save config: {}", e))
+    create_parent(&path)?;
+    backup_config(&path, &content)
+        .map_err(|e| anyhow::anyhow!("Failed to back up config: {}", e))?;
+
+    let dir = match path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(parent) => parent,
+        None => Path::new("."),
+    };
+    let save = || -> std::io::Result<()> {
+        // Created with 0600 on unix
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        file.write_all(content.as_bytes())?;
+        file.as_file().sync_all()?;
+        file.persist(&path)?;
+        Ok(())
+    };
+    save().map_err(|e| anyhow::anyhow!("Failed to save config: {}", e))
 }
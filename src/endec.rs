@@ -1,20 +1,32 @@
 use base64::{Engine, engine::general_purpose};
 
+/// Encoded strings are prefixed by their scheme, so that they can't be
+/// mistaken for plain ones.
+pub(crate) const BASE64_PREFIX: &str = "base64:";
+
 pub(crate) fn encode_string(s: String) -> Option<String> {
     if s.is_empty() {
         None
     } else {
         // base64 encode
-        Some(general_purpose::STANDARD.encode(s))
+        Some(format!(
+            "{}{}",
+            BASE64_PREFIX,
+            general_purpose::STANDARD.encode(s)
+        ))
     }
 }
 
-pub(crate) fn decode_string(s: &String) -> anyhow::Result<String> {
+pub(crate) fn decode_string(s: &str) -> anyhow::Result<String> {
     if s.is_empty() {
         anyhow::bail!("string is empty")
     } else {
-        // base64 decode
-        if let Ok(b) = general_purpose::STANDARD.decode(s) {
+        // Unprefixed strings were written in plain by hand, version 0
+        // configs are prefixed when they're migrated
+        let Some(encoded) = s.strip_prefix(BASE64_PREFIX) else {
+            return Ok(s.to_string());
+        };
+        if let Ok(b) = general_purpose::STANDARD.decode(encoded) {
             Ok(String::from_utf8(b)?)
         } else {
             anyhow::bail!("string decode failed")
@@ -0,0 +1,190 @@
+use russh::{Channel, client};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UnixStream,
+};
+
+/// Connects an agent channel opened by the server to the local ssh-agent.
+pub(crate) async fn proxy_agent(channel: Channel<client::Msg>) -> anyhow::Result<()> {
+    let agent = match std::env::var("SSH_AUTH_SOCK") {
+        Ok(path) => UnixStream::connect(path).await.ok(),
+        Err(_) => None,
+    };
+    let Some(mut agent) = agent else {
+        channel.close().await?;
+        anyhow::bail!("No local ssh-agent to forward to.")
+    };
+
+    let mut stream = channel.into_stream();
+    tokio::io::copy_bidirectional(&mut stream, &mut agent).await?;
+
+    Ok(())
+}
+
+const X11_AUTH_PROTOCOL: &str = "MIT-MAGIC-COOKIE-1";
+
+/// Cookies and display used for one X11 forwarding session, the server only
+/// ever sees the fake cookie which is swapped for the real one locally.
+#[derive(Debug, Clone)]
+pub(crate) struct X11Forward {
+    display: String,
+    pub(crate) screen: u32,
+    pub(crate) fake_cookie: String,
+    real_cookie: Option<Vec<u8>>,
+}
+
+impl X11Forward {
+    /// Reads the local `$DISPLAY` and its cookie, untrusted forwarding asks
+    /// `xauth` for a cookie restricted by the SECURITY extension.
+    pub(crate) async fn setup(trusted: bool) -> anyhow::Result<Self> {
+        let display = std::env::var("DISPLAY")
+            .map_err(|_| anyhow::anyhow!("DISPLAY is not set, X11 forwarding disabled."))?;
+        let screen = display
+            .rsplit_once(':')
+            .and_then(|(_, n)| n.split_once('.'))
+            .and_then(|(_, s)| s.parse().ok())
+            .unwrap_or(0);
+
+        let real_cookie = if trusted {
+            xauth_cookie(None, &display).await
+        } else {
+            let file = tempfile::NamedTempFile::new()?;
+            let generated = tokio::process::Command::new("xauth")
+                .arg("-f")
+                .arg(file.path())
+                .args(["generate", &display, X11_AUTH_PROTOCOL, "untrusted"])
+                .args(["timeout", "1200"])
+                .output()
+                .await;
+            if !generated.is_ok_and(|o| o.status.success()) {
+                anyhow::bail!(
+                    "Untrusted X11 forwarding setup failed, xauth key data not generated."
+                )
+            }
+            xauth_cookie(Some(file.path()), &display).await
+        };
+
+        let fake_cookie = rand::random::<[u8; 16]>()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        Ok(Self {
+            display,
+            screen,
+            fake_cookie,
+            real_cookie,
+        })
+    }
+
+    async fn connect(&self) -> anyhow::Result<Box<dyn Stream>> {
+        let (host, number) = self
+            .display
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Invalid DISPLAY {}.", self.display))?;
+        let number = number.split('.').next().unwrap_or_default();
+
+        // ":0" and "unix:0" live in /tmp/.X11-unix, a path (e.g. XQuartz on
+        // macOS) is the socket itself, anything else is a TCP display.
+        let stream: Box<dyn Stream> = if host.starts_with('/') {
+            Box::new(UnixStream::connect(host).await?)
+        } else if host.is_empty() || host == "unix" {
+            Box::new(UnixStream::connect(format!("/tmp/.X11-unix/X{}", number)).await?)
+        } else {
+            let port = 6000 + number.parse::<u16>()?;
+            Box::new(tokio::net::TcpStream::connect((host, port)).await?)
+        };
+        Ok(stream)
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+async fn xauth_cookie(file: Option<&std::path::Path>, display: &str) -> Option<Vec<u8>> {
+    let mut command = tokio::process::Command::new("xauth");
+    if let Some(file) = file {
+        command.arg("-f").arg(file);
+    }
+    let output = command.args(["list", display]).output().await.ok()?;
+
+    // Each line looks like "host/unix:0  MIT-MAGIC-COOKIE-1  <hex>"
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            match (fields.next(), fields.next()) {
+                (Some(X11_AUTH_PROTOCOL), Some(hex)) => decode_hex(hex),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn pad4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// Connects an X11 channel opened by the server to the local display,
+/// replacing the fake cookie in the client's connection setup.
+pub(crate) async fn proxy_x11(
+    channel: Channel<client::Msg>,
+    x11: X11Forward,
+) -> anyhow::Result<()> {
+    let mut stream = channel.into_stream();
+
+    // The setup starts with the byte order, the protocol version and the
+    // lengths of the authorization protocol name and data.
+    let mut header = [0u8; 12];
+    stream.read_exact(&mut header).await?;
+    let read_u16 = |at: usize| -> usize {
+        let bytes = [header[at], header[at + 1]];
+        match header[0] {
+            b'B' => u16::from_be_bytes(bytes) as usize,
+            _ => u16::from_le_bytes(bytes) as usize,
+        }
+    };
+    let (name_len, data_len) = (read_u16(6), read_u16(8));
+
+    let mut auth = vec![0u8; pad4(name_len) + pad4(data_len)];
+    stream.read_exact(&mut auth).await?;
+    let name = &auth[..name_len];
+    let data = &auth[pad4(name_len)..pad4(name_len) + data_len];
+
+    if name != X11_AUTH_PROTOCOL.as_bytes() || decode_hex(&x11.fake_cookie).as_deref() != Some(data)
+    {
+        stream.shutdown().await?;
+        anyhow::bail!("X11 connection rejected because of wrong authentication.")
+    }
+
+    let (name, data) = match &x11.real_cookie {
+        Some(cookie) => (X11_AUTH_PROTOCOL.as_bytes(), cookie.as_slice()),
+        None => (&[][..], &[][..]),
+    };
+    let write_u16 = |n: usize| match header[0] {
+        b'B' => (n as u16).to_be_bytes(),
+        _ => (n as u16).to_le_bytes(),
+    };
+    let mut setup = header[..6].to_vec();
+    setup.extend_from_slice(&write_u16(name.len()));
+    setup.extend_from_slice(&write_u16(data.len()));
+    setup.extend_from_slice(&header[10..]);
+    setup.extend_from_slice(name);
+    setup.resize(12 + pad4(name.len()), 0);
+    setup.extend_from_slice(data);
+    setup.resize(12 + pad4(name.len()) + pad4(data.len()), 0);
+
+    let mut display = x11.connect().await?;
+    display.write_all(&setup).await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut display).await?;
+
+    Ok(())
+}
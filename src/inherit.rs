@@ -0,0 +1,388 @@
+use crate::model::{Config, Server};
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeMap;
+use tabled::Tabled;
+
+/// Settings a server never inherits.
+const OWN_KEYS: [&str; 3] = ["name", "current", "extends"];
+
+/// Where an inherited setting came from.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Source {
+    Defaults,
+    Group(String),
+    Server(String),
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Defaults => write!(f, "defaults"),
+            Source::Group(group) => write!(f, "group {}", group),
+            Source::Server(name) => write!(f, "server {}", name),
+        }
+    }
+}
+
+/// A setting a server inherits, or would inherit if the server didn't clear
+/// it by setting it to null or empty.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Inherited {
+    pub(crate) source: Source,
+    pub(crate) value: Value,
+}
+
+/// Fills in the settings each server leaves out, from the server it
+/// `extends` (and so on up the chain), then the defaults of its group, then
+/// the top level `defaults`. Servers may also extend `included` ones, which
+/// are already resolved. Returns what each server inherited, in order.
+pub(crate) fn resolve(
+    value: &mut Value,
+    included: &[Server],
+) -> anyhow::Result<Vec<BTreeMap<String, Inherited>>> {
+    let Some(mapping) = value.as_mapping_mut() else {
+        anyhow::bail!("expected a mapping with a servers list")
+    };
+    let defaults = settings(mapping.get("defaults"), "defaults")?;
+    let groups = match mapping.get("groups") {
+        None | Some(Value::Null) => Mapping::new(),
+        Some(Value::Mapping(groups)) => groups.clone(),
+        Some(_) => anyhow::bail!("groups must map group names to settings"),
+    };
+    let Some(servers) = mapping.get_mut("servers").and_then(Value::as_sequence_mut) else {
+        return Ok(vec![]);
+    };
+
+    let mut raw: Vec<Mapping> = servers
+        .iter()
+        .map(|s| s.as_mapping().cloned().unwrap_or_default())
+        .collect();
+    for server in included {
+        if let Value::Mapping(mut mapping) = serde_yaml::to_value(server)? {
+            // Resolved already, and names in it refer to its own file
+            mapping.remove("extends");
+            raw.push(mapping);
+        }
+    }
+    let name_of = |server: &Mapping| {
+        server
+            .get("name")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+
+    let mut all_inherited = vec![];
+    for (server, own) in servers.iter_mut().zip(&raw) {
+        let name = name_of(own);
+        let mut layers: Vec<(Source, &Mapping)> = vec![];
+        let mut current = own;
+        while let Some(parent) = current.get("extends").and_then(Value::as_str) {
+            if parent == name
+                || layers
+                    .iter()
+                    .any(|(s, _)| *s == Source::Server(parent.into()))
+            {
+                anyhow::bail!("server {} extends itself through {}", name, parent)
+            }
+            let Some(found) = raw.iter().find(|s| name_of(s) == parent) else {
+                anyhow::bail!("server {} extends unknown server {}", name, parent)
+            };
+            layers.push((Source::Server(parent.to_string()), found));
+            current = found;
+        }
+
+        let group = [own]
+            .into_iter()
+            .chain(layers.iter().map(|(_, layer)| *layer))
+            .chain([&defaults])
+            .find_map(|layer| layer.get("group").and_then(Value::as_str));
+        let group_defaults = match group {
+            Some(group) => settings(groups.get(group), &format!("group {}", group))?,
+            None => Mapping::new(),
+        };
+        if let Some(group) = group {
+            layers.push((Source::Group(group.to_string()), &group_defaults));
+        }
+        layers.push((Source::Defaults, &defaults));
+
+        let mut inherited = BTreeMap::new();
+        for (source, layer) in layers {
+            for (key, value) in layer {
+                let Some(key) = key.as_str() else { continue };
+                let cleared = own.get(key).is_some_and(is_empty);
+                if OWN_KEYS.contains(&key)
+                    || (own.contains_key(key) && !cleared)
+                    || inherited.contains_key(key)
+                {
+                    continue;
+                }
+                inherited.insert(
+                    key.to_string(),
+                    Inherited {
+                        source: source.clone(),
+                        value: value.clone(),
+                    },
+                );
+            }
+        }
+
+        if let Some(server) = server.as_mapping_mut() {
+            for (key, inherited) in &inherited {
+                if !own.contains_key(key.as_str()) {
+                    server.insert(key.as_str().into(), inherited.value.clone());
+                }
+            }
+        }
+        all_inherited.push(inherited);
+    }
+    Ok(all_inherited)
+}
+
+/// Null, `[]` or `{}`, what a server sets a setting to so it isn't inherited.
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Sequence(items) => items.is_empty(),
+        Value::Mapping(mapping) => mapping.is_empty(),
+        _ => false,
+    }
+}
+
+fn settings(value: Option<&Value>, what: &str) -> anyhow::Result<Mapping> {
+    match value {
+        None | Some(Value::Null) => Ok(Mapping::new()),
+        Some(Value::Mapping(mapping)) => Ok(mapping.clone()),
+        Some(_) => anyhow::bail!("{} must be a mapping of settings", what),
+    }
+}
+
+/// The config as it's written to disk, leaving out what servers inherit
+/// unless it was changed since, and writing settings a server cleared as
+/// null or empty. Settings inherited from a server that no longer exists are
+/// written out in full. Servers from included files are left out.
+pub(crate) fn unresolve(config: &Config) -> anyhow::Result<Value> {
+    let mut value = serde_yaml::to_value(config)?;
+    let servers = value
+        .get_mut("servers")
+        .and_then(Value::as_sequence_mut)
+        .into_iter()
+        .flatten();
+    for (mapping, server) in servers.zip(&config.servers) {
+        let Some(mapping) = mapping.as_mapping_mut() else {
+            continue;
+        };
+        let exists = |name: &str| config.servers.iter().any(|s| s.name == name);
+        if server
+            .extends
+            .as_deref()
+            .is_some_and(|parent| !exists(parent))
+        {
+            mapping.remove("extends");
+        }
+
+        for (key, inherited) in &server.inherited {
+            if let Source::Server(parent) = &inherited.source
+                && !exists(parent)
+            {
+                continue;
+            }
+            match mapping.get(key.as_str()) {
+                Some(value) if *value == inherited.value => {
+                    mapping.remove(key.as_str());
+                }
+                Some(_) => {}
+                None if is_empty(&inherited.value) => {}
+                // Cleared, keep it from being inherited again
+                None => {
+                    let empty = match inherited.value {
+                        Value::Sequence(_) => Value::Sequence(vec![]),
+                        Value::Mapping(_) => Value::Mapping(Mapping::new()),
+                        _ => Value::Null,
+                    };
+                    mapping.insert(key.as_str().into(), empty);
+                }
+            }
+        }
+    }
+    // Kept until here, servers may extend them
+    if let Some(servers) = value.get_mut("servers").and_then(Value::as_sequence_mut) {
+        let mut own = config.servers.iter().map(|s| !s.read_only());
+        servers.retain(|_| own.next().unwrap_or(true));
+    }
+    Ok(value)
+}
+
+/// Points everything inherited from server `from` at `to` after a rename.
+pub(crate) fn rename_parent(config: &mut Config, from: &str, to: &str) {
+    for server in &mut config.servers {
+        if server.extends.as_deref() == Some(from) {
+            server.extends = Some(to.to_string());
+        }
+        for inherited in server.inherited.values_mut() {
+            if inherited.source == Source::Server(from.to_string()) {
+                inherited.source = Source::Server(to.to_string());
+            }
+        }
+    }
+}
+
+const OWN_SOURCE: &str = "this server";
+
+#[derive(Debug, Clone, Tabled)]
+#[tabled(rename_all = "UPPERCASE")]
+pub(crate) struct Setting {
+    pub(crate) field: String,
+    pub(crate) value: String,
+    pub(crate) source: String,
+}
+
+/// The settings of `server`, only the ones set on the server itself unless
+/// `resolved`, in which case inherited ones are included along with where
+/// they came from.
+pub(crate) fn server_settings(server: &Server, resolved: bool) -> anyhow::Result<Vec<Setting>> {
+    let Value::Mapping(mapping) = serde_yaml::to_value(server)? else {
+        unreachable!("a server always serializes to a mapping")
+    };
+
+    let mut settings = vec![];
+    for (key, value) in &mapping {
+        let Some(key) = key.as_str() else { continue };
+        let source = match server.inherited.get(key) {
+            Some(inherited) if *value == inherited.value => inherited.source.to_string(),
+            Some(_) | None => OWN_SOURCE.to_string(),
+        };
+        if key == "current" || (!resolved && source != OWN_SOURCE) {
+            continue;
+        }
+        let value = if matches!(key, "password" | "passphrase") {
+            "******".to_string()
+        } else {
+            display_value(value)
+        };
+        settings.push(Setting {
+            field: key.to_string(),
+            value,
+            source,
+        });
+    }
+    Ok(settings)
+}
+
+fn display_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => s.clone(),
+        Value::Sequence(items) => items
+            .iter()
+            .map(display_value)
+            .collect::<Vec<_>>()
+            .join(", "),
+        Value::Mapping(mapping) => mapping
+            .iter()
+            .map(|(k, v)| format!("{}={}", display_value(k), display_value(v)))
+            .collect::<Vec<_>>()
+            .join(", "),
+        Value::Tagged(tagged) => display_value(&tagged.value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    /// Saves `config` and loads it back, as the next command would see it.
+    fn save_and_load(config: &Config) -> (String, Config) {
+        let content = serde_yaml::to_string(&unresolve(config).unwrap()).unwrap();
+        let loaded = parse_config(&content, &[]).unwrap();
+        (content, loaded)
+    }
+
+    const CONFIG: &str = "
+defaults:
+  user: deploy
+  port: 22
+  identity_file: ~/.ssh/id_ed25519
+  tags: [web]
+servers:
+- name: base
+  host: 10.0.0.1
+  send_env: [LANG]
+- name: child
+  host: 10.0.0.2
+  extends: base
+  identity_file: null
+  tags: []
+";
+
+    #[test]
+    fn round_trip_leaves_inherited_settings_out() {
+        let config = parse_config(CONFIG, &[]).unwrap();
+        let child = &config.servers[1];
+        assert_eq!(child.user, "deploy");
+        assert_eq!(child.send_env, ["LANG"]);
+
+        let value = unresolve(&config).unwrap();
+        let child = &value["servers"][1];
+        assert!(child.get("user").is_none());
+        assert!(child.get("send_env").is_none());
+
+        let (_, loaded) = save_and_load(&config);
+        assert_eq!(
+            serde_yaml::to_value(&loaded.servers).unwrap(),
+            serde_yaml::to_value(&config.servers).unwrap()
+        );
+    }
+
+    #[test]
+    fn clears_survive_round_trips() {
+        let config = parse_config(CONFIG, &[]).unwrap();
+        let child = &config.servers[1];
+        assert_eq!(child.identity_file, None);
+        assert!(child.tags.is_empty());
+
+        let (first, loaded) = save_and_load(&config);
+        let (second, loaded) = save_and_load(&loaded);
+        assert_eq!(first, second);
+        let child = &loaded.servers[1];
+        assert_eq!(child.identity_file, None);
+        assert!(child.tags.is_empty());
+        assert_eq!(loaded.servers[0].tags, ["web"]);
+    }
+
+    #[test]
+    fn removing_an_inherited_setting_clears_it() {
+        let mut config = parse_config(CONFIG, &[]).unwrap();
+        config.servers[0].send_env.clear();
+
+        let (_, loaded) = save_and_load(&config);
+        assert!(loaded.servers[0].send_env.is_empty());
+        assert!(loaded.servers[1].send_env.is_empty());
+    }
+
+    #[test]
+    fn nothing_to_clear_when_inherited_empty() {
+        let config = parse_config(
+            "
+defaults:
+  tags: []
+  identity_file: null
+servers:
+- name: a
+  host: 10.0.0.1
+  port: 22
+  user: root
+",
+            &[],
+        )
+        .unwrap();
+
+        let value = unresolve(&config).unwrap();
+        let server = &value["servers"][0];
+        assert!(server.get("tags").is_none());
+        assert!(server.get("identity_file").is_none());
+    }
+}
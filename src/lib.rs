@@ -1,9 +1,24 @@
+mod backup;
 pub mod cli;
+mod cluster;
 mod cmd;
 pub mod colord_print;
 mod common;
 mod config;
 mod endec;
+#[cfg(unix)]
+mod forward;
+mod inherit;
+mod migration;
 mod model;
+#[cfg(unix)]
+mod mux;
+mod parallel;
 mod prompt;
+mod record;
+mod remote_edit;
+mod sftp;
+mod sftp_shell;
 mod ssh;
+mod sync;
+mod transcript;
@@ -4,6 +4,7 @@ use ssher::{cli::Cli, colord_print::red};
 async fn main() -> anyhow::Result<()> {
     if let Err(e) = Cli::new().run().await {
         red(e.to_string());
+        std::process::exit(1);
     }
 
     std::process::exit(0);
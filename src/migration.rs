@@ -0,0 +1,105 @@
+use crate::endec::BASE64_PREFIX;
+use serde_yaml::{Mapping, Value};
+
+/// Bumped whenever the layout of the config changes, along with a new
+/// entry in `MIGRATIONS`.
+pub(crate) const CONFIG_VERSION: u64 = 1;
+
+type Migration = fn(&mut Mapping) -> anyhow::Result<()>;
+
+/// `MIGRATIONS[n]` upgrades a version `n` config to version `n + 1`.
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize] = [prefix_passwords];
+
+/// Upgrades a parsed config file to the current version. Files without a
+/// `version` key predate versioning and count as version 0.
+pub(crate) fn migrate(value: &mut Value) -> anyhow::Result<()> {
+    let Some(mapping) = value.as_mapping_mut() else {
+        anyhow::bail!("expected a mapping with a servers list")
+    };
+
+    let version = match mapping.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| anyhow::anyhow!("invalid version {:?}", version))?,
+    };
+    if version > CONFIG_VERSION {
+        anyhow::bail!(
+            "it was written by a newer ssher (config version {}, this ssher understands up to {}), please upgrade ssher",
+            version,
+            CONFIG_VERSION
+        )
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(mapping)?;
+    }
+    mapping.insert("version".into(), CONFIG_VERSION.into());
+    Ok(())
+}
+
+fn servers(mapping: &mut Mapping) -> impl Iterator<Item = &mut Mapping> {
+    mapping
+        .get_mut("servers")
+        .and_then(Value::as_sequence_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_mapping_mut)
+}
+
+/// Version 0 stored passwords as bare base64, which can't be told apart
+/// from a plain password. Version 1 prefixes them with their encoding.
+fn prefix_passwords(mapping: &mut Mapping) -> anyhow::Result<()> {
+    for server in servers(mapping) {
+        if let Some(Value::String(password)) = server.get_mut("password")
+            && !password.is_empty()
+        {
+            password.insert_str(0, BASE64_PREFIX);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unversioned_passwords_get_prefixed() {
+        let mut value: Value = serde_yaml::from_str(
+            "
+servers:
+- name: a
+  password: cHc=
+- name: b
+  password: ''
+- name: c
+",
+        )
+        .unwrap();
+        migrate(&mut value).unwrap();
+
+        assert_eq!(value["version"], Value::from(CONFIG_VERSION));
+        assert_eq!(value["servers"][0]["password"], Value::from("base64:cHc="));
+        assert_eq!(value["servers"][1]["password"], Value::from(""));
+        assert!(value["servers"][2].get("password").is_none());
+    }
+
+    #[test]
+    fn current_configs_are_left_alone() {
+        let content = "version: 1\nservers:\n- name: a\n  password: base64:cHc=\n";
+        let mut value: Value = serde_yaml::from_str(content).unwrap();
+        migrate(&mut value).unwrap();
+        assert_eq!(value, serde_yaml::from_str::<Value>(content).unwrap());
+    }
+
+    #[test]
+    fn newer_and_invalid_versions_are_rejected() {
+        let mut newer: Value = serde_yaml::from_str("version: 99\nservers: []\n").unwrap();
+        assert!(migrate(&mut newer).is_err());
+        let mut invalid: Value = serde_yaml::from_str("version: one\nservers: []\n").unwrap();
+        assert!(migrate(&mut invalid).is_err());
+        let mut list: Value = serde_yaml::from_str("- a\n").unwrap();
+        assert!(migrate(&mut list).is_err());
+    }
+}
@@ -1,12 +1,26 @@
+use crate::{
+    common::wildcard_match,
+    endec,
+    inherit::{Inherited, Source, resolve},
+};
 use serde::{Deserialize, Serialize};
+use serde_yaml::Mapping;
+use std::{collections::BTreeMap, path::PathBuf};
 use tabled::Tabled;
 
-#[derive(Serialize, Deserialize, Debug, Clone, Tabled)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Tabled)]
 #[tabled(rename_all = "UPPERCASE")]
 pub(crate) struct Server {
+    /// May contain `*` and `?` wildcards, see `is_pattern`.
     pub(crate) name: String,
+    /// Patterns may leave host, port and user out, `%h` in a pattern's host
+    /// expands to the host being connected to.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub(crate) host: String,
+    #[serde(default, skip_serializing_if = "is_unset_port")]
+    #[tabled(display = "display_port")]
     pub(crate) port: u16,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub(crate) user: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[tabled(display = "display_password")]
@@ -15,11 +29,84 @@ pub(crate) struct Server {
     #[tabled(display("tabled::derive::display::option", ""))]
     #[tabled(rename = "IDENTITY FILE")]
     pub(crate) identity_file: Option<String>,
+    /// Unlocks the identity file, encoded like the password.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub(crate) passphrase: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[tabled(display = "display_option_bool")]
     #[tabled(rename = "")]
     #[tabled(order = 0)]
     pub(crate) current: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub(crate) control_master: Option<bool>,
+    /// Seconds an idle mux master stays alive, 0 keeps it alive until stopped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub(crate) control_persist: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub(crate) forward_agent: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub(crate) forward_x11: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub(crate) forward_x11_trusted: Option<bool>,
+    /// Local variables to forward, `*` and `?` wildcards are allowed e.g. `LC_*`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[tabled(skip)]
+    pub(crate) send_env: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[tabled(skip)]
+    pub(crate) set_env: BTreeMap<String, String>,
+    /// Runs instead of the login shell, e.g. `tmux new -A -s main`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub(crate) remote_command: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub(crate) remote_dir: Option<String>,
+    /// Session output is appended here, `%n` and `%d` expand to the server
+    /// name and the date e.g. `~/ssher-logs/%n-%d.log`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub(crate) log_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub(crate) log_strip_ansi: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub(crate) log_timestamp: Option<bool>,
+    /// Record interactive sessions as asciicast v2 files.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub(crate) record: Option<bool>,
+    /// Defaults to `~/.ssher/recordings/%n-%d-%t.cast`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub(crate) record_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(display("tabled::derive::display::option", ""))]
+    pub(crate) group: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[tabled(display = "display_tags")]
+    pub(crate) tags: Vec<String>,
+    /// Name of a server to inherit unspecified settings from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub(crate) extends: Option<String>,
+    /// Settings filled in from `extends`, group defaults or defaults when
+    /// loading, left out again when saving unless changed.
+    #[serde(skip)]
+    #[tabled(skip)]
+    pub(crate) inherited: BTreeMap<String, Inherited>,
+    /// The included file the server comes from, `None` for servers of the
+    /// config itself.
+    #[serde(skip)]
+    #[tabled(display = "display_source")]
+    pub(crate) source: Option<PathBuf>,
 }
 
 impl From<sshconfig::HostEntry> for Server {
@@ -31,7 +118,7 @@ impl From<sshconfig::HostEntry> for Server {
             user: host.user,
             password: None,
             identity_file: host.identity_file,
-            current: None,
+            ..Default::default()
         }
     }
 }
@@ -46,20 +133,570 @@ impl Server {
             user: "root".to_string(),
             password: None,
             identity_file: "~/.ssh/id_rsa".to_string().into(),
-            current: None,
+            ..Default::default()
+        }
+    }
+
+    /// Patterns like `*.prod.example.com` aren't connected to themselves,
+    /// their settings apply to hosts matching them instead.
+    pub(crate) fn is_pattern(&self) -> bool {
+        self.name.contains(['*', '?'])
+    }
+
+    /// Servers from included files can't be changed through ssher.
+    pub(crate) fn read_only(&self) -> bool {
+        self.source.is_some()
+    }
+
+    /// Returns a copy with one field set from a `key=value` option, keys are
+    /// field names in either `identity_file` or ssh_config style `IdentityFile`.
+    pub(crate) fn with_option(&self, key: &str, value: &str) -> anyhow::Result<Self> {
+        let field = option_field(key);
+        if !field_names().contains(&field.as_str()) {
+            anyhow::bail!("😿 Unknown option {}.", key)
+        }
+        if matches!(field.as_str(), "name" | "current" | "extends") {
+            anyhow::bail!("😿 Option {} can't be overridden.", key)
+        }
+
+        // Try the value as YAML first so numbers, booleans and lists keep
+        // their types, then ssh_config's yes/no, then a comma separated list
+        // like `add --tag` takes, then a plain string.
+        let yes_no = match value.to_lowercase().as_str() {
+            "yes" => Some(serde_yaml::Value::Bool(true)),
+            "no" => Some(serde_yaml::Value::Bool(false)),
+            _ => None,
+        };
+        let list = value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| serde_yaml::Value::String(item.to_string()))
+            .collect();
+        let string = serde_yaml::Value::String(value.to_string());
+        let yaml = serde_yaml::from_str::<serde_yaml::Value>(value)
+            .ok()
+            // `~` is a home directory rather than null for string settings
+            .filter(|yaml| !yaml.is_null() || self.with_field(&field, string.clone()).is_err());
+        let candidates = yaml
+            .into_iter()
+            .chain(yes_no)
+            .chain([serde_yaml::Value::Sequence(list), string]);
+        for candidate in candidates {
+            if let Ok(server) = self.with_field(&field, candidate) {
+                return Ok(server);
+            }
+        }
+
+        anyhow::bail!("😿 Invalid value {} for option {}.", value, key)
+    }
+
+    /// Returns a copy with `field` set to `value`, if it's valid for it.
+    fn with_field(&self, field: &str, value: serde_yaml::Value) -> anyhow::Result<Self> {
+        let mut mapping = match serde_yaml::to_value(self)? {
+            serde_yaml::Value::Mapping(mapping) => mapping,
+            _ => unreachable!("a server always serializes to a mapping"),
+        };
+        mapping.insert(field.into(), value);
+        let server: Self = serde_yaml::from_value(mapping.into())?;
+        Ok(self.with_state_of(server))
+    }
+
+    /// Like `with_option`, but secrets given in plain text are stored
+    /// encoded, as the config keeps them.
+    pub(crate) fn with_secret_or_option(&self, key: &str, value: &str) -> anyhow::Result<Self> {
+        let mut server = self.clone();
+        match option_field(key).as_str() {
+            "password" => server.password = endec::encode_string(value.to_string()),
+            "passphrase" => server.passphrase = endec::encode_string(value.to_string()),
+            _ => server = self.with_option(key, value)?,
+        }
+        Ok(server)
+    }
+
+    /// Returns a copy with one field cleared, keys are as in `with_option`.
+    pub(crate) fn without_option(&self, key: &str) -> anyhow::Result<Self> {
+        let field = option_field(key);
+        if !field_names().contains(&field.as_str()) {
+            anyhow::bail!("😿 Unknown option {}.", key)
+        }
+        if matches!(field.as_str(), "name" | "current") {
+            anyhow::bail!("😿 Option {} can't be unset.", key)
+        }
+
+        let mut mapping = match serde_yaml::to_value(self)? {
+            serde_yaml::Value::Mapping(mapping) => mapping,
+            _ => unreachable!("a server always serializes to a mapping"),
+        };
+        mapping.remove(field.as_str());
+        let server: Self = serde_yaml::from_value(mapping.into())?;
+        Ok(self.with_state_of(server))
+    }
+
+    /// `server` along with what isn't stored in the config, which
+    /// round-tripping through YAML loses.
+    fn with_state_of(&self, server: Self) -> Self {
+        Self {
+            inherited: self.inherited.clone(),
+            source: self.source.clone(),
+            ..server
+        }
+    }
+
+    /// The first required field that's missing, patterns don't require any.
+    pub(crate) fn missing_field(&self) -> Option<&'static str> {
+        if self.is_pattern() {
+            None
+        } else if self.host.is_empty() {
+            Some("host")
+        } else if is_unset_port(&self.port) {
+            Some("port")
+        } else if self.user.is_empty() {
+            Some("user")
+        } else {
+            None
+        }
+    }
+
+    /// Builds a transient server from `[user@]host[:port]` or
+    /// `ssh://[user@]host[:port]`, IPv6 hosts go in brackets.
+    pub fn from_destination(destination: &str) -> anyhow::Result<Self> {
+        let (user, host, port) = parse_destination(destination)?;
+
+        let mut server = Self::new(host);
+        server.port = port.unwrap_or(server.port);
+        server.user = user
+            .or_else(|| std::env::var("USER").ok())
+            .unwrap_or(server.user);
+
+        Ok(server)
+    }
+}
+
+/// Turns ssh_config style `IdentityFile` or `identity-file` into the field
+/// name `identity_file`, and `HostName` into `host`.
+pub(crate) fn option_field(key: &str) -> String {
+    let mut field = String::new();
+    for (i, c) in key.chars().enumerate() {
+        if c.is_uppercase() && i > 0 && !field.ends_with('_') {
+            field.push('_');
+        }
+        field.extend(c.to_lowercase());
+    }
+    match field.replace('-', "_").as_str() {
+        // ssh_config's name for it
+        "host_name" | "hostname" => "host".to_string(),
+        field => field.to_string(),
+    }
+}
+
+/// The names of a server's fields in the config, as serde sees them.
+fn field_names() -> &'static [&'static str] {
+    use serde::de::{self, Visitor};
+
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> de::Deserializer<'de> for FieldNames<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("only structs have field names"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("field names captured"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = Server::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+/// Splits `[ssh://][user@]host[:port]` into its parts.
+pub(crate) fn parse_destination(
+    destination: &str,
+) -> anyhow::Result<(Option<String>, String, Option<u16>)> {
+    let rest = destination.strip_prefix("ssh://").unwrap_or(destination);
+    let rest = rest.trim_end_matches('/');
+
+    let (user, rest) = match rest.rsplit_once('@') {
+        Some((user, rest)) => (Some(user.to_string()), rest),
+        None => (None, rest),
+    };
+
+    let (host, port) = if let Some(rest) = rest.strip_prefix('[') {
+        let (host, rest) = rest
+            .split_once(']')
+            .ok_or_else(|| anyhow::anyhow!("😿 Invalid destination {}.", destination))?;
+        (host, rest.strip_prefix(':'))
+    } else {
+        match rest.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (rest, None),
         }
+    };
+
+    if host.is_empty() || user.as_ref().is_some_and(|u| u.is_empty()) {
+        anyhow::bail!("😿 Invalid destination {}.", destination)
     }
+
+    let port = port
+        .map(|port| port.parse())
+        .transpose()
+        .map_err(|_| anyhow::anyhow!("😿 Invalid port in destination {}.", destination))?;
+
+    Ok((user, host.to_string(), port))
 }
 
 pub(crate) fn display_password(value: &Option<String>) -> String {
     value.as_ref().map_or("", |_| "******").to_string()
 }
 
+fn is_unset_port(port: &u16) -> bool {
+    *port == 0
+}
+
+pub(crate) fn display_port(value: &u16) -> String {
+    if is_unset_port(value) {
+        String::new()
+    } else {
+        value.to_string()
+    }
+}
+
+pub(crate) fn display_tags(value: &[String]) -> String {
+    value.join(", ")
+}
+
+pub(crate) fn display_source(value: &Option<PathBuf>) -> String {
+    value
+        .as_ref()
+        .map_or(String::new(), |path| format!("🔒 {}", path.display()))
+}
+
 pub(crate) fn display_option_bool(value: &Option<bool>) -> String {
     value.map_or(" ", |v| if v { "✲" } else { " " }).to_string()
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Narrows servers down by group and tags, `*` and `?` wildcards are
+/// allowed in both.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ServerFilter {
+    /// A server needs all of these tags to match.
+    pub(crate) tags: Vec<String>,
+    pub(crate) group: Option<String>,
+}
+
+impl ServerFilter {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.group.is_none()
+    }
+
+    pub(crate) fn matches(&self, server: &Server) -> bool {
+        let group = self.group.as_ref().is_none_or(|pattern| {
+            server
+                .group
+                .as_ref()
+                .is_some_and(|group| wildcard_match(pattern, group))
+        });
+        let tags = self
+            .tags
+            .iter()
+            .all(|pattern| server.tags.iter().any(|tag| wildcard_match(pattern, tag)));
+        group && tags
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct Config {
+    /// Layout version of the file, see `migration::CONFIG_VERSION`.
+    #[serde(default)]
+    pub(crate) version: u64,
+    /// Files to read more servers from, e.g. a team's shared inventory.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) include: Vec<String>,
+    /// Settings every server inherits unless it sets them itself.
+    #[serde(default, skip_serializing_if = "Mapping::is_empty")]
+    pub(crate) defaults: Mapping,
+    /// Settings the servers of a group inherit, by group name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) groups: BTreeMap<String, Mapping>,
     pub(crate) servers: Vec<Server>,
 }
+
+impl Config {
+    /// Pattern servers matching `host`, in the order they're listed.
+    pub(crate) fn matching_patterns<'a>(
+        &'a self,
+        host: &'a str,
+    ) -> impl Iterator<Item = &'a Server> {
+        self.servers
+            .iter()
+            .filter(move |s| s.is_pattern() && wildcard_match(&s.name, host))
+    }
+
+    /// A transient server for `destination` with the settings of the
+    /// patterns its host matches. As with `Host` patterns in ssh_config, the
+    /// first pattern to set something wins, and the user and port given in
+    /// the destination win over all of them. Group defaults and `defaults`
+    /// come last, whether any pattern matched or not.
+    pub(crate) fn destination_server(&self, destination: &str) -> anyhow::Result<Server> {
+        let (user, host, port) = parse_destination(destination)?;
+
+        let mut mapping = Mapping::new();
+        for pattern in self.matching_patterns(&host) {
+            let serde_yaml::Value::Mapping(settings) = serde_yaml::to_value(pattern)? else {
+                unreachable!("a server always serializes to a mapping")
+            };
+            for (key, value) in settings {
+                let own = matches!(key.as_str(), Some("name" | "current" | "extends"));
+                // Defaults resolved into an earlier pattern mustn't win over
+                // what a later one sets, they're applied once below
+                let defaulted = key
+                    .as_str()
+                    .and_then(|key| pattern.inherited.get(key))
+                    .is_some_and(|inherited| {
+                        inherited.value == value
+                            && matches!(inherited.source, Source::Defaults | Source::Group(_))
+                    });
+                if !own && !defaulted && !mapping.contains_key(&key) {
+                    mapping.insert(key, value);
+                }
+            }
+        }
+        if let Some(host_name) = mapping.get("host").and_then(|h| h.as_str()) {
+            let host_name = host_name.replace("%h", &host);
+            mapping.insert("host".into(), host_name.into());
+        }
+        if let Some(user) = user {
+            mapping.insert("user".into(), user.into());
+        }
+        if let Some(port) = port {
+            mapping.insert("port".into(), port.into());
+        }
+
+        // Resolved as a server of the config would be, for group defaults
+        // and defaults
+        let mut config: serde_yaml::Value = Mapping::from_iter([
+            ("defaults".into(), self.defaults.clone().into()),
+            ("groups".into(), serde_yaml::to_value(&self.groups)?),
+            (
+                "servers".into(),
+                vec![serde_yaml::Value::from(mapping)].into(),
+            ),
+        ])
+        .into();
+        let inherited = resolve(&mut config, &[])?;
+        let Some(serde_yaml::Value::Mapping(mut mapping)) = config
+            .get_mut("servers")
+            .and_then(|servers| servers.as_sequence_mut())
+            .and_then(|servers| servers.pop())
+        else {
+            unreachable!("the server was put there above")
+        };
+
+        let serde_yaml::Value::Mapping(fallback) =
+            serde_yaml::to_value(Server::from_destination(destination)?)?
+        else {
+            unreachable!("a server always serializes to a mapping")
+        };
+        for (key, value) in fallback {
+            if !mapping.contains_key(&key) {
+                mapping.insert(key, value);
+            }
+        }
+        let mut server: Server = serde_yaml::from_value(mapping.into())?;
+        server.inherited = inherited.into_iter().next().unwrap_or_default();
+        Ok(server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    const CONFIG: &str = "
+defaults:
+  user: deploy
+  port: 2200
+groups:
+  db:
+    remote_dir: /srv/db
+servers:
+- name: '*.prod'
+  identity_file: ~/.ssh/prod
+- name: db*.prod
+  user: dba
+  group: db
+  identity_file: ~/.ssh/db
+";
+
+    #[test]
+    fn first_pattern_to_set_something_wins() {
+        let config = parse_config(CONFIG, &[]).unwrap();
+        let server = config.destination_server("db1.prod").unwrap();
+        assert_eq!(server.host, "db1.prod");
+        assert_eq!(server.identity_file.as_deref(), Some("~/.ssh/prod"));
+        assert_eq!(server.remote_dir.as_deref(), Some("/srv/db"));
+    }
+
+    #[test]
+    fn patterns_win_over_defaults() {
+        let config = parse_config(CONFIG, &[]).unwrap();
+        let server = config.destination_server("db1.prod").unwrap();
+        assert_eq!(server.user, "dba");
+        assert_eq!(server.port, 2200);
+
+        let server = config.destination_server("web1.prod").unwrap();
+        assert_eq!(server.user, "deploy");
+    }
+
+    #[test]
+    fn destination_wins_over_patterns() {
+        let config = parse_config(CONFIG, &[]).unwrap();
+        let server = config.destination_server("root@db1.prod:22").unwrap();
+        assert_eq!(server.user, "root");
+        assert_eq!(server.port, 22);
+    }
+
+    #[test]
+    fn defaults_apply_without_patterns() {
+        let config = parse_config(CONFIG, &[]).unwrap();
+        let server = config.destination_server("10.0.0.1").unwrap();
+        assert_eq!(server.host, "10.0.0.1");
+        assert_eq!(server.user, "deploy");
+        assert_eq!(server.port, 2200);
+        assert_ne!(server.identity_file.as_deref(), Some("~/.ssh/prod"));
+    }
+
+    #[test]
+    fn options_split_lists() {
+        let server = Server::new("10.0.0.1".to_string());
+        let server = server.with_option("tags", "web, prod").unwrap();
+        assert_eq!(server.tags, ["web", "prod"]);
+        let server = server.with_option("SendEnv", "LANG").unwrap();
+        assert_eq!(server.send_env, ["LANG"]);
+        let server = server.with_option("tags", "[db]").unwrap();
+        assert_eq!(server.tags, ["db"]);
+    }
+
+    #[test]
+    fn options_take_empty_lists() {
+        let server = Server::new("10.0.0.1".to_string())
+            .with_option("tags", "web")
+            .unwrap();
+        assert!(server.with_option("tags", "[]").unwrap().tags.is_empty());
+        assert!(
+            server
+                .with_option("send_env", "")
+                .unwrap()
+                .send_env
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn options_take_yes_and_no() {
+        let server = Server::new("10.0.0.1".to_string());
+        let server = server.with_option("ForwardAgent", "yes").unwrap();
+        assert_eq!(server.forward_agent, Some(true));
+        let server = server.with_option("forward-agent", "no").unwrap();
+        assert_eq!(server.forward_agent, Some(false));
+    }
+
+    #[test]
+    fn options_keep_tilde_for_strings() {
+        let server = Server::new("10.0.0.1".to_string());
+        let server = server.with_option("RemoteDir", "~").unwrap();
+        assert_eq!(server.remote_dir.as_deref(), Some("~"));
+        let server = server.with_option("HostName", "10.0.0.2").unwrap();
+        assert_eq!(server.host, "10.0.0.2");
+        let server = server.with_option("Port", "0").unwrap();
+        assert_eq!(server.port, 0);
+    }
+
+    #[test]
+    fn options_must_be_known_and_valid() {
+        let server = Server::new("10.0.0.1".to_string());
+        assert!(server.with_option("nope", "1").is_err());
+        assert!(server.with_option("name", "other").is_err());
+        assert!(server.with_option("port", "ssh").is_err());
+    }
+
+    #[test]
+    fn destinations_split_into_parts() {
+        let parse = |destination| parse_destination(destination).unwrap();
+        assert_eq!(parse("10.0.0.1"), (None, "10.0.0.1".to_string(), None));
+        assert_eq!(
+            parse("ssh://deploy@web1:2222/"),
+            (Some("deploy".to_string()), "web1".to_string(), Some(2222))
+        );
+        assert_eq!(
+            parse("root@[::1]:22"),
+            (Some("root".to_string()), "::1".to_string(), Some(22))
+        );
+        assert_eq!(parse("[fe80::1]"), (None, "fe80::1".to_string(), None));
+    }
+
+    #[test]
+    fn invalid_destinations_are_rejected() {
+        assert!(parse_destination("web1:ssh").is_err());
+        assert!(parse_destination("web1:70000").is_err());
+        assert!(parse_destination("@web1").is_err());
+        assert!(parse_destination("root@").is_err());
+        assert!(parse_destination("[::1").is_err());
+    }
+
+    #[test]
+    fn destination_servers_default_port() {
+        let server = Server::from_destination("deploy@[::1]").unwrap();
+        assert_eq!(server.host, "::1");
+        assert_eq!(server.user, "deploy");
+        assert_eq!(server.port, 22);
+    }
+
+    #[test]
+    fn secrets_are_stored_encoded() {
+        let server = Server::new("10.0.0.1".to_string());
+        for key in ["password", "Password"] {
+            let password = server
+                .with_secret_or_option(key, "s3cret")
+                .unwrap()
+                .password;
+            assert_ne!(password.as_deref(), Some("s3cret"));
+            assert_eq!(endec::decode_string(&password.unwrap()).unwrap(), "s3cret");
+        }
+        let passphrase = server
+            .with_secret_or_option("Passphrase", "s3cret")
+            .unwrap()
+            .passphrase;
+        assert_eq!(
+            endec::decode_string(&passphrase.unwrap()).unwrap(),
+            "s3cret"
+        );
+    }
+
+    #[test]
+    fn option_keys_map_to_fields() {
+        assert_eq!(option_field("IdentityFile"), "identity_file");
+        assert_eq!(option_field("identity-file"), "identity_file");
+        assert_eq!(option_field("remote_dir"), "remote_dir");
+        assert_eq!(option_field("HostName"), "host");
+        assert_eq!(option_field("hostname"), "host");
+        assert_eq!(option_field("ForwardX11Trusted"), "forward_x11_trusted");
+    }
+}
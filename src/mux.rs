@@ -0,0 +1,604 @@
+use crate::{
+    model::Server,
+    ssh::{ChannelRequest, Session},
+};
+use russh::{ChannelMsg, CryptoVec};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    os::unix::{fs::DirBuilderExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tabled::Tabled;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+    sync::{
+        Notify,
+        mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    },
+};
+
+/// Seconds an idle master waits for new clients before exiting.
+const DEFAULT_CONTROL_PERSIST: u64 = 600;
+/// Seconds a client waits for a freshly spawned master to come up.
+const MASTER_STARTUP_TIMEOUT: u64 = 30;
+/// Frames are far smaller than this, a longer one is corrupt or hostile.
+const MAX_FRAME_LEN: usize = 1 << 20;
+
+const FRAME_OPEN: u8 = 1;
+const FRAME_OPENED: u8 = 2;
+const FRAME_DATA: u8 = 3;
+const FRAME_EXTENDED_DATA: u8 = 4;
+const FRAME_EOF: u8 = 5;
+const FRAME_WINDOW_CHANGE: u8 = 6;
+const FRAME_EXIT_STATUS: u8 = 7;
+const FRAME_CLOSE: u8 = 8;
+const FRAME_ERROR: u8 = 9;
+const FRAME_STATUS: u8 = 10;
+const FRAME_STOP: u8 = 11;
+
+/// Messages exchanged between a mux master and its clients, each one is
+/// written as a tag byte, a big-endian u32 length and the payload.
+#[derive(Debug)]
+enum Frame {
+    Open(ChannelRequest),
+    Opened,
+    Data(Vec<u8>),
+    ExtendedData(u32, Vec<u8>),
+    Eof,
+    WindowChange(u32, u32),
+    ExitStatus(u32),
+    Close,
+    Error(String),
+    /// Sent empty by a client, answered by the master with its status.
+    Status(Option<MuxStatus>),
+    Stop,
+}
+
+impl Frame {
+    fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let (tag, payload) = match self {
+            Frame::Open(request) => (FRAME_OPEN, serde_yaml::to_string(request)?.into_bytes()),
+            Frame::Opened => (FRAME_OPENED, vec![]),
+            Frame::Data(data) => (FRAME_DATA, data.clone()),
+            Frame::ExtendedData(ext, data) => {
+                let mut payload = ext.to_be_bytes().to_vec();
+                payload.extend_from_slice(data);
+                (FRAME_EXTENDED_DATA, payload)
+            }
+            Frame::Eof => (FRAME_EOF, vec![]),
+            Frame::WindowChange(col, row) => {
+                let mut payload = col.to_be_bytes().to_vec();
+                payload.extend_from_slice(&row.to_be_bytes());
+                (FRAME_WINDOW_CHANGE, payload)
+            }
+            Frame::ExitStatus(status) => (FRAME_EXIT_STATUS, status.to_be_bytes().to_vec()),
+            Frame::Close => (FRAME_CLOSE, vec![]),
+            Frame::Error(message) => (FRAME_ERROR, message.clone().into_bytes()),
+            Frame::Status(None) => (FRAME_STATUS, vec![]),
+            Frame::Status(Some(status)) => {
+                (FRAME_STATUS, serde_yaml::to_string(status)?.into_bytes())
+            }
+            Frame::Stop => (FRAME_STOP, vec![]),
+        };
+
+        let mut buf = Vec::with_capacity(payload.len() + 5);
+        buf.push(tag);
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&payload);
+        Ok(buf)
+    }
+
+    fn decode(tag: u8, payload: Vec<u8>) -> anyhow::Result<Self> {
+        let u32_at = |i: usize| -> anyhow::Result<u32> {
+            let bytes = payload
+                .get(i..i + 4)
+                .ok_or_else(|| anyhow::anyhow!("Truncated mux frame."))?;
+            Ok(u32::from_be_bytes(bytes.try_into()?))
+        };
+
+        let frame = match tag {
+            FRAME_OPEN => Frame::Open(serde_yaml::from_slice(&payload)?),
+            FRAME_OPENED => Frame::Opened,
+            FRAME_DATA => Frame::Data(payload),
+            FRAME_EXTENDED_DATA => Frame::ExtendedData(u32_at(0)?, payload[4..].to_vec()),
+            FRAME_EOF => Frame::Eof,
+            FRAME_WINDOW_CHANGE => Frame::WindowChange(u32_at(0)?, u32_at(4)?),
+            FRAME_EXIT_STATUS => Frame::ExitStatus(u32_at(0)?),
+            FRAME_CLOSE => Frame::Close,
+            FRAME_ERROR => Frame::Error(String::from_utf8_lossy(&payload).into_owned()),
+            FRAME_STATUS if payload.is_empty() => Frame::Status(None),
+            FRAME_STATUS => Frame::Status(Some(serde_yaml::from_slice(&payload)?)),
+            FRAME_STOP => Frame::Stop,
+            _ => anyhow::bail!("Unknown mux frame {}.", tag),
+        };
+        Ok(frame)
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<Frame>> {
+    let tag = match reader.read_u8().await {
+        Ok(tag) => tag,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let len = reader.read_u32().await? as usize;
+    if len > MAX_FRAME_LEN {
+        anyhow::bail!("Mux frame of {} bytes is too long.", len)
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+
+    Frame::decode(tag, payload).map(Some)
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame) -> anyhow::Result<()> {
+    writer.write_all(&frame.encode()?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Moves frame reading and writing onto background tasks, so that receiving
+/// stays cancel safe inside `tokio::select!`.
+fn spawn_frame_io(stream: UnixStream) -> (UnboundedSender<Frame>, UnboundedReceiver<Frame>) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (in_tx, in_rx) = unbounded_channel();
+    let (out_tx, mut out_rx) = unbounded_channel::<Frame>();
+
+    tokio::spawn(async move {
+        while let Ok(Some(frame)) = read_frame(&mut reader).await {
+            if in_tx.send(frame).is_err() {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(frame) = out_rx.recv().await {
+            if write_frame(&mut writer, &frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    (out_tx, in_rx)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tabled)]
+#[tabled(rename_all = "UPPERCASE")]
+pub(crate) struct MuxStatus {
+    pub(crate) name: String,
+    pub(crate) target: String,
+    pub(crate) pid: u32,
+    pub(crate) channels: usize,
+    #[tabled(display = "display_uptime")]
+    pub(crate) uptime: u64,
+}
+
+fn display_uptime(value: &u64) -> String {
+    let (h, m, s) = (value / 3600, value / 60 % 60, value % 60);
+    if h > 0 {
+        format!("{}h{}m{}s", h, m, s)
+    } else if m > 0 {
+        format!("{}m{}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+pub(crate) fn socket_dir() -> PathBuf {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join("ssher"),
+        // SAFETY: getuid has no preconditions and can't fail
+        _ => std::env::temp_dir().join(format!("ssher-{}", unsafe { libc::getuid() })),
+    }
+}
+
+/// Refuses a socket directory someone else could have planted sockets in,
+/// e.g. one another user created first in the shared temp dir.
+fn check_socket_dir(dir: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let metadata = std::fs::symlink_metadata(dir)?;
+    // SAFETY: getuid has no preconditions and can't fail
+    let uid = unsafe { libc::getuid() };
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.permissions().mode() & 0o777 != 0o700
+    {
+        anyhow::bail!(
+            "😿 {} must be a directory owned by you with mode 0700, refusing to use it.",
+            dir.display()
+        )
+    }
+    Ok(())
+}
+
+/// Connects to a socket after checking the directory it's in.
+async fn connect_socket(path: &Path) -> anyhow::Result<UnixStream> {
+    check_socket_dir(path.parent().unwrap_or(Path::new("/")))?;
+    Ok(UnixStream::connect(path).await?)
+}
+
+/// Masters are keyed by destination rather than by name, so that renaming a
+/// server doesn't orphan its master. The destination is hashed to keep long
+/// host names within the length limit of socket paths.
+pub(crate) fn socket_path(server: &Server) -> PathBuf {
+    let target = format!("{}@{}:{}", server.user, server.host, server.port);
+    let hash = format!("{:x}", Sha256::digest(target.as_bytes()));
+    socket_dir().join(format!("{}.sock", &hash[..32]))
+}
+
+/// Asks the master listening on `path` for its status.
+pub(crate) async fn query(path: &Path) -> anyhow::Result<MuxStatus> {
+    let mut stream = connect_socket(path).await?;
+    write_frame(&mut stream, &Frame::Status(None)).await?;
+    match read_frame(&mut stream).await? {
+        Some(Frame::Status(Some(status))) => Ok(status),
+        _ => anyhow::bail!("Unexpected reply from mux master."),
+    }
+}
+
+pub(crate) async fn stop(path: &Path) -> anyhow::Result<()> {
+    let mut stream = connect_socket(path).await?;
+    write_frame(&mut stream, &Frame::Stop).await
+}
+
+/// Lists the running masters, removing sockets left behind by dead ones.
+pub(crate) async fn masters() -> anyhow::Result<Vec<(PathBuf, MuxStatus)>> {
+    let mut masters = vec![];
+    let dir = socket_dir();
+    let Ok(entries) = std::fs::read_dir(&dir) else {
+        return Ok(masters);
+    };
+    check_socket_dir(&dir)?;
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "sock") {
+            continue;
+        }
+        match query(&path).await {
+            Ok(status) => masters.push((path, status)),
+            Err(_) => {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+    }
+    masters.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+
+    Ok(masters)
+}
+
+pub(crate) struct MuxClient {
+    path: PathBuf,
+}
+
+impl MuxClient {
+    pub(crate) async fn open(&self, request: &ChannelRequest) -> anyhow::Result<MuxChannel> {
+        let stream = connect_socket(&self.path).await?;
+        let (tx, mut rx) = spawn_frame_io(stream);
+        tx.send(Frame::Open(request.clone()))?;
+
+        match rx.recv().await {
+            Some(Frame::Opened) => Ok(MuxChannel { tx, rx }),
+            Some(Frame::Error(e)) => anyhow::bail!("😿 {}", e),
+            _ => anyhow::bail!("😿 Mux master closed the connection."),
+        }
+    }
+}
+
+/// Connects to the master for `server`, starting one in the background if
+/// none is running yet.
+pub(crate) async fn connect(server: &Server) -> anyhow::Result<MuxClient> {
+    let path = socket_path(server);
+    if query(&path).await.is_ok() {
+        return Ok(MuxClient { path });
+    }
+
+    let mut child = spawn_master(server)?;
+    let started = Instant::now();
+    loop {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if query(&path).await.is_ok() {
+            return Ok(MuxClient { path });
+        }
+        if child.try_wait()?.is_some() {
+            anyhow::bail!("Mux master for {} exited during startup", server.name)
+        }
+        if started.elapsed() > Duration::from_secs(MASTER_STARTUP_TIMEOUT) {
+            anyhow::bail!("Mux master for {} did not start in time", server.name)
+        }
+    }
+}
+
+/// Starts `ssher mux master` in its own process group, the server is handed
+/// over on stdin so that secrets never show up in the process list.
+fn spawn_master(server: &Server) -> anyhow::Result<std::process::Child> {
+    let mut child = std::process::Command::new(std::env::current_exe()?)
+        .args(["mux", "master"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        use std::io::Write;
+        stdin.write_all(serde_yaml::to_string(server)?.as_bytes())?;
+    }
+
+    Ok(child)
+}
+
+pub(crate) struct MuxChannel {
+    tx: UnboundedSender<Frame>,
+    rx: UnboundedReceiver<Frame>,
+}
+
+impl MuxChannel {
+    fn send(&self, frame: Frame) -> anyhow::Result<()> {
+        self.tx
+            .send(frame)
+            .map_err(|_| anyhow::anyhow!("😿 Mux master closed the connection."))
+    }
+
+    pub(crate) fn data(&self, data: &[u8]) -> anyhow::Result<()> {
+        self.send(Frame::Data(data.to_vec()))
+    }
+
+    pub(crate) fn eof(&self) -> anyhow::Result<()> {
+        self.send(Frame::Eof)
+    }
+
+    pub(crate) fn window_change(&self, col: u32, row: u32) -> anyhow::Result<()> {
+        self.send(Frame::WindowChange(col, row))
+    }
+
+    pub(crate) async fn wait(&mut self) -> Option<ChannelMsg> {
+        loop {
+            let msg = match self.rx.recv().await? {
+                Frame::Data(data) => ChannelMsg::Data {
+                    data: CryptoVec::from(data),
+                },
+                Frame::ExtendedData(ext, data) => ChannelMsg::ExtendedData {
+                    data: CryptoVec::from(data),
+                    ext,
+                },
+                Frame::Eof => ChannelMsg::Eof,
+                Frame::ExitStatus(exit_status) => ChannelMsg::ExitStatus { exit_status },
+                Frame::Close => ChannelMsg::Close,
+                _ => continue,
+            };
+            return Some(msg);
+        }
+    }
+}
+
+struct Master {
+    server: Server,
+    session: Session,
+    started: Instant,
+    channels: AtomicUsize,
+    stop: Notify,
+}
+
+impl Master {
+    fn status(&self) -> MuxStatus {
+        MuxStatus {
+            name: self.server.name.clone(),
+            target: format!(
+                "{}@{}:{}",
+                self.server.user, self.server.host, self.server.port
+            ),
+            pid: std::process::id(),
+            channels: self.channels.load(Ordering::SeqCst),
+            uptime: self.started.elapsed().as_secs(),
+        }
+    }
+}
+
+/// Runs a mux master for `server` until it is stopped, the connection drops
+/// or it has been idle for `control_persist` seconds.
+pub(crate) async fn serve(server: Server) -> anyhow::Result<()> {
+    let path = socket_path(&server);
+    if query(&path).await.is_ok() {
+        anyhow::bail!("😿 A mux master for {} is already running.", server.name)
+    }
+
+    let dir = socket_dir();
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)?;
+    check_socket_dir(&dir)?;
+
+    let session = Session::connect_direct(server.clone()).await?;
+
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+
+    let persist = Duration::from_secs(server.control_persist.unwrap_or(DEFAULT_CONTROL_PERSIST));
+    let master = Arc::new(Master {
+        server,
+        session,
+        started: Instant::now(),
+        channels: AtomicUsize::new(0),
+        stop: Notify::new(),
+    });
+
+    let mut idle_since = Instant::now();
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                if let Ok((stream, _)) = accepted {
+                    tokio::spawn(handle_client(stream, master.clone()));
+                }
+            }
+            _ = master.stop.notified() => break,
+            _ = tick.tick() => {
+                if master.session.is_closed() {
+                    break;
+                }
+                if master.channels.load(Ordering::SeqCst) > 0 {
+                    idle_since = Instant::now();
+                } else if !persist.is_zero() && idle_since.elapsed() >= persist {
+                    break;
+                }
+            }
+        }
+    }
+
+    let _ = std::fs::remove_file(&path);
+    master.session.close().await
+}
+
+async fn handle_client(stream: UnixStream, master: Arc<Master>) {
+    let (tx, mut rx) = spawn_frame_io(stream);
+
+    let request = match rx.recv().await {
+        Some(Frame::Open(request)) => request,
+        Some(Frame::Status(None)) => {
+            let _ = tx.send(Frame::Status(Some(master.status())));
+            return;
+        }
+        Some(Frame::Stop) => {
+            master.stop.notify_one();
+            return;
+        }
+        _ => return,
+    };
+
+    let channel = match master.session.open_direct(&request).await {
+        Ok(channel) => channel,
+        Err(e) => {
+            let _ = tx.send(Frame::Error(e.to_string()));
+            return;
+        }
+    };
+    let _ = tx.send(Frame::Opened);
+
+    master.channels.fetch_add(1, Ordering::SeqCst);
+    let _ = relay(channel, tx, rx).await;
+    master.channels.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Shuttles traffic between a client and its channel until either side goes away.
+async fn relay(
+    channel: russh::Channel<russh::client::Msg>,
+    tx: UnboundedSender<Frame>,
+    mut rx: UnboundedReceiver<Frame>,
+) -> anyhow::Result<()> {
+    let (mut reader, writer) = channel.split();
+
+    loop {
+        tokio::select! {
+            frame = rx.recv() => match frame {
+                Some(Frame::Data(data)) => writer.data(&data[..]).await?,
+                Some(Frame::Eof) => writer.eof().await?,
+                Some(Frame::WindowChange(col, row)) => writer.window_change(col, row, 0, 0).await?,
+                Some(_) => {}
+                // The client is gone, don't leave the remote side hanging
+                None => {
+                    writer.close().await?;
+                    break;
+                }
+            },
+            msg = reader.wait() => {
+                let frame = match msg {
+                    Some(ChannelMsg::Data { data }) => Frame::Data(data.to_vec()),
+                    Some(ChannelMsg::ExtendedData { data, ext }) => Frame::ExtendedData(ext, data.to_vec()),
+                    Some(ChannelMsg::Eof) => Frame::Eof,
+                    Some(ChannelMsg::ExitStatus { exit_status }) => Frame::ExitStatus(exit_status),
+                    Some(ChannelMsg::Close) | None => {
+                        let _ = tx.send(Frame::Close);
+                        break;
+                    }
+                    _ => continue,
+                };
+                if tx.send(frame).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn round_trip(frame: Frame) -> Frame {
+        let encoded = frame.encode().unwrap();
+        read_frame(&mut &encoded[..]).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let request = ChannelRequest {
+            env: vec![("LANG".to_string(), "C".to_string())],
+            command: Some("uptime".to_string()),
+            ..Default::default()
+        };
+        let frames = [
+            Frame::Open(request),
+            Frame::Opened,
+            Frame::Data(b"hello".to_vec()),
+            Frame::ExtendedData(1, b"oops".to_vec()),
+            Frame::Eof,
+            Frame::WindowChange(80, 24),
+            Frame::ExitStatus(3),
+            Frame::Close,
+            Frame::Error("refused".to_string()),
+            Frame::Status(None),
+            Frame::Stop,
+        ];
+        for frame in frames {
+            let expected = format!("{:?}", frame);
+            assert_eq!(format!("{:?}", round_trip(frame).await), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn end_of_stream_is_no_frame() {
+        assert!(read_frame(&mut &[][..]).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn bad_frames_are_rejected() {
+        let mut too_long = vec![FRAME_DATA];
+        too_long.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert!(read_frame(&mut &too_long[..]).await.is_err());
+
+        let truncated = [FRAME_EXIT_STATUS, 0, 0, 0, 2, 0, 1];
+        assert!(read_frame(&mut &truncated[..]).await.is_err());
+
+        let unknown = [0xff, 0, 0, 0, 0];
+        assert!(read_frame(&mut &unknown[..]).await.is_err());
+    }
+
+    #[test]
+    fn socket_names_have_a_fixed_length() {
+        let mut server = Server::new("a".repeat(200));
+        let long = socket_path(&server);
+        server.host = "10.0.0.1".to_string();
+        let short = socket_path(&server);
+
+        assert_ne!(long, short);
+        assert_eq!(
+            long.file_name().unwrap().len(),
+            "0123456789abcdef0123456789abcdef.sock".len()
+        );
+        assert_eq!(
+            long.file_name().unwrap().len(),
+            short.file_name().unwrap().len()
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use crate::{
+    colord_print::HOST_COLORS,
+    model::Server,
+    ssh::{Session, exec_request},
+};
+use console::{Color, style};
+use russh::ChannelMsg;
+use std::{
+    io::Write,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tabled::Tabled;
+use tokio::sync::Semaphore;
+
+#[derive(Debug, Clone, Tabled)]
+#[tabled(rename_all = "UPPERCASE")]
+pub(crate) struct ExecResult {
+    #[tabled(rename = "SERVER")]
+    pub(crate) name: String,
+    #[tabled(display = "display_status")]
+    pub(crate) status: Result<u32, String>,
+    #[tabled(display = "display_duration")]
+    pub(crate) duration: Duration,
+    #[tabled(skip)]
+    output: Vec<(bool, Vec<u8>)>,
+}
+
+impl ExecResult {
+    pub(crate) fn success(&self) -> bool {
+        matches!(self.status, Ok(0))
+    }
+}
+
+fn display_status(value: &Result<u32, String>) -> String {
+    match value {
+        Ok(code) => format!("exit {}", code),
+        Err(e) => e.clone(),
+    }
+}
+
+fn display_duration(value: &Duration) -> String {
+    format!("{:.1}s", value.as_secs_f64())
+}
+
+/// Runs `command` on every server, at most `jobs` at a time. Output is
+/// streamed as lines prefixed by the server name, or printed per server
+/// once everything finished when `group` is set.
+pub(crate) async fn run(
+    servers: Vec<Server>,
+    command: String,
+    jobs: usize,
+    group: bool,
+) -> anyhow::Result<Vec<ExecResult>> {
+    let width = servers.iter().map(|s| s.name.len()).max().unwrap_or(0);
+    let semaphore = Arc::new(Semaphore::new(jobs.max(1)));
+
+    let tasks: Vec<_> = servers
+        .into_iter()
+        .enumerate()
+        .map(|(i, server)| {
+            let semaphore = semaphore.clone();
+            let command = command.clone();
+            let prefix = Prefix {
+                name: format!("{:<width$} | ", server.name, width = width),
+                color: HOST_COLORS[i % HOST_COLORS.len()],
+                stream: !group,
+                pending: Default::default(),
+            };
+            tokio::spawn(async move {
+                let _permit = semaphore.acquire_owned().await?;
+                anyhow::Ok(run_one(server, &command, prefix).await)
+            })
+        })
+        .collect();
+
+    let mut results = vec![];
+    for task in tasks {
+        results.push(task.await??);
+    }
+
+    if group {
+        let mut stdout = std::io::stdout();
+        let mut stderr = std::io::stderr();
+        for result in &results {
+            let header = format!(
+                "── {} ({}, {}) ──",
+                result.name,
+                display_status(&result.status),
+                display_duration(&result.duration)
+            );
+            let header = if result.success() {
+                style(header).green()
+            } else {
+                style(header).red()
+            };
+            println!("{}", header);
+            for (is_stderr, data) in &result.output {
+                if *is_stderr {
+                    stderr.write_all(data)?;
+                } else {
+                    stdout.write_all(data)?;
+                }
+            }
+            if result
+                .output
+                .last()
+                .is_some_and(|(_, data)| !data.ends_with(b"\n"))
+            {
+                println!();
+            }
+        }
+        stdout.flush()?;
+        stderr.flush()?;
+    }
+
+    Ok(results)
+}
+
+async fn run_one(server: Server, command: &str, mut prefix: Prefix) -> ExecResult {
+    let name = server.name.clone();
+    let started = Instant::now();
+    let mut output = vec![];
+
+    let status = async {
+        let request = exec_request(&server, command);
+        let session = Session::connect(server).await?;
+        let mut channel = session.open_channel(&request).await?;
+
+        let mut code = None;
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::Data { ref data } => {
+                    prefix.write(false, data);
+                    output.push((false, data.to_vec()));
+                }
+                ChannelMsg::ExtendedData { ref data, .. } => {
+                    prefix.write(true, data);
+                    output.push((true, data.to_vec()));
+                }
+                ChannelMsg::ExitStatus { exit_status } => code = Some(exit_status),
+                _ => {}
+            }
+        }
+        prefix.finish();
+        let _ = session.close().await;
+
+        code.ok_or_else(|| anyhow::anyhow!("no exit status"))
+    }
+    .await
+    .map_err(|e| e.to_string());
+
+    ExecResult {
+        name,
+        status,
+        duration: started.elapsed(),
+        output,
+    }
+}
+
+/// Prints a server's output line by line, each line prefixed by its name.
+struct Prefix {
+    name: String,
+    color: Color,
+    stream: bool,
+    /// Unfinished lines of stdout and stderr.
+    pending: [Vec<u8>; 2],
+}
+
+impl Prefix {
+    fn write(&mut self, is_stderr: bool, data: &[u8]) {
+        if !self.stream {
+            return;
+        }
+        let pending = &mut self.pending[is_stderr as usize];
+        pending.extend_from_slice(data);
+        // Whole lines only, so that concurrent servers don't interleave
+        // within a line
+        if let Some(end) = pending.iter().rposition(|&b| b == b'\n') {
+            let lines: Vec<u8> = pending.drain(..=end).collect();
+            self.print(is_stderr, &lines);
+        }
+    }
+
+    fn finish(&mut self) {
+        for is_stderr in [false, true] {
+            let rest = std::mem::take(&mut self.pending[is_stderr as usize]);
+            if !rest.is_empty() {
+                self.print(is_stderr, &rest);
+            }
+        }
+    }
+
+    fn print(&self, is_stderr: bool, data: &[u8]) {
+        let prefix = style(&self.name).fg(self.color).to_string();
+        let mut buf = vec![];
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            buf.extend_from_slice(prefix.as_bytes());
+            buf.extend_from_slice(line);
+            if !line.ends_with(b"\n") {
+                buf.push(b'\n');
+            }
+        }
+        let _ = if is_stderr {
+            std::io::stderr().lock().write_all(&buf)
+        } else {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&buf).and_then(|_| stdout.flush())
+        };
+    }
+}
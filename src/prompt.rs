@@ -1,12 +1,11 @@
 #![allow(dead_code)]
 use crate::{
-    cmd,
     colord_print::yellow,
     endec,
     model::{Config, Server},
 };
 use dialoguer::{
-    Confirm, Input, Password, Select,
+    Confirm, Input, MultiSelect, Password, Select,
     console::{Style, style},
     theme::ColorfulTheme,
 };
@@ -34,60 +33,111 @@ pub(crate) fn default_theme() -> ColorfulTheme {
     }
 }
 
-pub(crate) fn servers_select_prompt(servers: &[Server]) -> Option<Server> {
+/// Servers ordered by group, ungrouped ones first, along with the header
+/// to show above the first server of each group.
+fn grouped_servers(servers: &[Server]) -> Vec<(Option<String>, &Server)> {
+    let mut sorted: Vec<&Server> = servers.iter().collect();
+    sorted.sort_by_key(|s| s.group.as_deref());
+
+    let mut previous = None;
+    sorted
+        .into_iter()
+        .map(|s| {
+            let header = match s.group.as_deref() {
+                Some(group) if previous != Some(group) => {
+                    Some(style(format!("── {} ──", group)).dim().to_string())
+                }
+                _ => None,
+            };
+            previous = s.group.as_deref();
+            (header, s)
+        })
+        .collect()
+}
+
+/// `(user@host:port)`, or just `(pattern)` for patterns which may not have
+/// them, followed by the server's tags if it has any.
+fn describe(server: &Server) -> String {
+    let target = if server.is_pattern() {
+        "pattern".to_string()
+    } else {
+        format!("{}@{}:{}", server.user, server.host, server.port)
+    };
+    if server.tags.is_empty() {
+        format!("({})", target)
+    } else {
+        format!("({}) [{}]", target, server.tags.join(", "))
+    }
+}
+
+/// Puts `header` on a line of its own above `item`, indented to line up
+/// with the item prefixes.
+fn with_header(header: &Option<String>, item: String) -> String {
+    match header {
+        Some(header) => format!("{}\n  {}", header, item),
+        None => item,
+    }
+}
+
+/// What was picked in `servers_select_prompt`.
+pub(crate) enum ServerChoice {
+    Server(Box<Server>),
+    /// "Add a new server", left to the caller which may hold the config lock.
+    Add,
+    Exit,
+}
+
+pub(crate) fn servers_select_prompt(servers: &[Server]) -> ServerChoice {
     let max_name_width = servers.iter().map(|s| s.name.len()).max().unwrap_or(0);
-    let mut selections: Vec<String> = servers
+    let grouped = grouped_servers(servers);
+    let mut selections: Vec<String> = grouped
         .iter()
-        .map(|s| {
+        .enumerate()
+        .map(|(i, (header, s))| {
             let prefix = match s.current {
                 Some(true) => "✲ ",
                 _ => "  ",
             };
-            let subfix = match servers.len() {
-                1.. if s.name == servers[servers.len() - 1].name => "\n",
-                _ => "",
-            };
+            let subfix = if i == grouped.len() - 1 { "\n" } else { "" };
 
-            format!(
-                "{}{:<width$}\t({}@{}:{}){}",
+            let item = format!(
+                "{}{:<width$}\t{}{}",
                 prefix,
                 s.name,
-                s.user,
-                s.host,
-                s.port,
+                describe(s),
                 subfix,
                 width = max_name_width
-            )
+            );
+            with_header(header, item)
         })
         .collect();
 
     selections.push("✚ Add a new server".to_string());
     selections.push("✗ Exit".to_string());
 
-    let current_server_index = servers.iter().position(|s| s.current == Some(true));
+    let current_server_index = grouped.iter().position(|(_, s)| s.current == Some(true));
 
     let selection = Select::with_theme(&default_theme())
         .with_prompt("Select a server:")
         .default(current_server_index.unwrap_or_default())
         .report(false)
         .items(&selections)
-        .interact()
-        .ok()?;
+        .interact();
+    let Ok(selection) = selection else {
+        return ServerChoice::Exit;
+    };
 
     // Add a new server
     if selection == selections.len() - 2 {
-        if let Err(e) = cmd::add_server() {
-            yellow(format!("😾 {}", e));
-        }
-        return None;
+        return ServerChoice::Add;
     }
 
     // Exit
     if selection == selections.len() - 1 {
-        return None;
+        return ServerChoice::Exit;
     }
 
-    Some(servers[selection].clone())
+    ServerChoice::Server(Box::new(grouped[selection].1.clone()))
 }
 
 fn server_form_prompt(server: &Server, config: &Config) -> anyhow::Result<Option<Server>> {
@@ -105,10 +155,12 @@ fn server_form_prompt(server: &Server, config: &Config) -> anyhow::Result<Option
         .allow_empty(false)
         .interact_text()?;
 
+    // Patterns may leave the host to the destination being connected to
+    let is_pattern = name.contains(['*', '?']);
     let host: String = Input::with_theme(&default_theme())
-        .with_prompt("Host(*):")
+        .with_prompt(if is_pattern { "Host:" } else { "Host(*):" })
         .with_initial_text(server.host.clone())
-        .allow_empty(false)
+        .allow_empty(is_pattern)
         .interact_text()?;
 
     let port: u16 = Input::with_theme(&default_theme())
@@ -126,10 +178,7 @@ fn server_form_prompt(server: &Server, config: &Config) -> anyhow::Result<Option
         .allow_empty(false)
         .interact_text()?;
 
-    let password: String = Password::with_theme(&default_theme())
-        .with_prompt("Password:")
-        .allow_empty_password(true)
-        .interact()?;
+    let password = secret_prompt("Password:", &server.password)?;
 
     let identity_file: String = Input::with_theme(&default_theme())
         .with_prompt("IdentityFile:")
@@ -142,21 +191,75 @@ fn server_form_prompt(server: &Server, config: &Config) -> anyhow::Result<Option
         .allow_empty(true)
         .interact_text()?;
 
+    let passphrase = if identity_file.is_empty() {
+        None
+    } else {
+        secret_prompt("Key passphrase:", &server.passphrase)?
+    };
+
+    let group: String = Input::with_theme(&default_theme())
+        .with_prompt("Group:")
+        .with_initial_text(server.group.clone().unwrap_or_default())
+        .allow_empty(true)
+        .interact_text()?;
+
+    let tags: String = Input::with_theme(&default_theme())
+        .with_prompt("Tags (comma separated):")
+        .with_initial_text(server.tags.join(","))
+        .allow_empty(true)
+        .interact_text()?;
+
     Ok(Some(Server {
         name,
         host,
         port,
         user,
-        password: endec::encode_string(password),
+        password,
+        passphrase,
         identity_file: if identity_file.is_empty() {
             None
         } else {
             Some(identity_file)
         },
+        group: Some(group.trim().to_string()).filter(|g| !g.is_empty()),
+        tags: tags
+            .split(',')
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect(),
         current: None,
+        ..server.clone()
     }))
 }
 
+/// Asks for a secret, returned encoded. A stored one can't be shown, so it's
+/// kept unless changing or clearing it is picked.
+fn secret_prompt(prompt: &str, current: &Option<String>) -> anyhow::Result<Option<String>> {
+    if current.as_ref().is_none_or(|c| c.is_empty()) {
+        let secret = Password::with_theme(&default_theme())
+            .with_prompt(prompt)
+            .allow_empty_password(true)
+            .interact()?;
+        return Ok(endec::encode_string(secret));
+    }
+
+    let selection = Select::with_theme(&default_theme())
+        .with_prompt(prompt)
+        .default(0)
+        .items(&["Keep", "Change", "Clear"])
+        .interact()?;
+    match selection {
+        0 => Ok(current.clone()),
+        1 => {
+            let secret = Password::with_theme(&default_theme())
+                .with_prompt(format!("New {}", prompt.to_lowercase()))
+                .interact()?;
+            Ok(endec::encode_string(secret))
+        }
+        _ => Ok(None),
+    }
+}
+
 pub(crate) fn add_server_form_prompt(config: &Config) -> anyhow::Result<Option<Server>> {
     let default_server = Server::new("".to_string());
     server_form_prompt(&default_server, config)
@@ -169,6 +272,41 @@ pub(crate) fn edit_server_form_prompt(
     server_form_prompt(server, config)
 }
 
+pub(crate) fn input_prompt(prompt: &str) -> anyhow::Result<String> {
+    let res = Input::with_theme(&default_theme())
+        .with_prompt(prompt)
+        .allow_empty(false)
+        .interact_text()?;
+
+    Ok(res)
+}
+
+pub(crate) fn password_prompt(prompt: &str) -> anyhow::Result<String> {
+    let res = Password::with_theme(&default_theme())
+        .with_prompt(prompt)
+        .report(false)
+        .interact()?;
+
+    Ok(res)
+}
+
+pub(crate) fn server_name_prompt(config: &Config, server: &Server) -> anyhow::Result<String> {
+    let res = Input::with_theme(&default_theme())
+        .with_prompt("Name(*):")
+        .with_initial_text(server.name.clone())
+        .validate_with(|input: &String| {
+            if config.servers.iter().any(|s| s.name == *input) {
+                Err(format!("😾 Name {} already exists.", input))
+            } else {
+                Ok(())
+            }
+        })
+        .allow_empty(false)
+        .interact_text()?;
+
+    Ok(res)
+}
+
 pub(crate) fn confirm_prompt(prompt: &str) -> anyhow::Result<bool> {
     let res = Confirm::with_theme(&default_theme())
         .with_prompt(prompt)
@@ -213,3 +351,28 @@ pub(crate) fn rename_server_prompt(config: &Config, server: &Server) -> anyhow::
 
     Ok(res)
 }
+
+pub(crate) fn servers_multi_select_prompt(servers: &[Server]) -> anyhow::Result<Vec<Server>> {
+    let max_name_width = servers.iter().map(|s| s.name.len()).max().unwrap_or(0);
+    let grouped = grouped_servers(servers);
+    let selections: Vec<String> = grouped
+        .iter()
+        .map(|(header, s)| {
+            let item = format!(
+                "{:<width$}\t{}",
+                s.name,
+                describe(s),
+                width = max_name_width
+            );
+            with_header(header, item)
+        })
+        .collect();
+
+    let selected = MultiSelect::with_theme(&default_theme())
+        .with_prompt("Select servers (space to toggle):")
+        .report(false)
+        .items(&selections)
+        .interact()?;
+
+    Ok(selected.into_iter().map(|i| grouped[i].1.clone()).collect())
+}
@@ -0,0 +1,195 @@
+use crate::{common::expand_path_template, model::Server};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+use tokio::io::AsyncWriteExt;
+
+const DEFAULT_RECORD_FILE: &str = "~/.ssher/recordings/%n-%d-%t.cast";
+
+/// Header line of an asciicast v2 file, see
+/// https://docs.asciinema.org/manual/asciicast/v2/
+#[derive(Serialize, Deserialize, Debug)]
+struct Header {
+    version: u8,
+    width: u16,
+    height: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    idle_time_limit: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+}
+
+/// Records session output as asciicast v2, playable by `ssher play` and asciinema.
+pub(crate) struct Recorder {
+    pub(crate) path: String,
+    writer: BufWriter<File>,
+    started: Instant,
+    /// Trailing bytes of a UTF-8 character split across writes.
+    pending: Vec<u8>,
+}
+
+impl Recorder {
+    /// Starts a recording when the server has `record` set, `None` otherwise.
+    pub(crate) fn open(server: &Server, width: u16, height: u16) -> anyhow::Result<Option<Self>> {
+        if !server.record.unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let template = server.record_file.as_deref().unwrap_or(DEFAULT_RECORD_FILE);
+        let path = expand_path_template(template, server);
+        if let Some(parent) = Path::new(&path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Sessions may show secrets, keep the recording to ourselves
+        let mut options = fs::OpenOptions::new();
+        options.create(true).truncate(true).write(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("😿 Failed to create recording {}: {}", path, e))?;
+
+        let header = Header {
+            version: 2,
+            width,
+            height,
+            timestamp: Some(chrono::Local::now().timestamp()),
+            idle_time_limit: None,
+            title: Some(format!(
+                "{} ({}@{}:{})",
+                server.name, server.user, server.host, server.port
+            )),
+            env: ["SHELL", "TERM"]
+                .into_iter()
+                .filter_map(|name| Some((name.to_string(), std::env::var(name).ok()?)))
+                .collect(),
+        };
+
+        let mut recorder = Self {
+            path,
+            writer: BufWriter::new(file),
+            started: Instant::now(),
+            pending: vec![],
+        };
+        writeln!(recorder.writer, "{}", serde_json::to_string(&header)?)?;
+        recorder.writer.flush()?;
+
+        Ok(Some(recorder))
+    }
+
+    pub(crate) fn output(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.pending.extend_from_slice(data);
+
+        let text = match std::str::from_utf8(&self.pending) {
+            Ok(text) => text.to_string(),
+            // Keep an incomplete character for the next write
+            Err(e) if e.error_len().is_none() => {
+                let tail = self.pending.split_off(e.valid_up_to());
+                let text = String::from_utf8_lossy(&self.pending).into_owned();
+                self.pending = tail;
+                return self.event("o", &text);
+            }
+            Err(_) => String::from_utf8_lossy(&self.pending).into_owned(),
+        };
+        self.pending.clear();
+
+        self.event("o", &text)
+    }
+
+    pub(crate) fn resize(&mut self, width: u16, height: u16) -> anyhow::Result<()> {
+        self.event("r", &format!("{}x{}", width, height))
+    }
+
+    fn event(&mut self, code: &str, data: &str) -> anyhow::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let time = self.started.elapsed().as_secs_f64();
+        writeln!(
+            self.writer,
+            "{}",
+            serde_json::to_string(&(time, code, data))?
+        )?;
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Replays an asciicast v2 file to stdout. Pauses longer than
+/// `idle_time_limit` (or the file's own limit) are shortened to it.
+pub(crate) async fn play(
+    path: &str,
+    speed: f64,
+    idle_time_limit: Option<f64>,
+) -> anyhow::Result<()> {
+    if !(speed.is_finite() && speed > 0.0) {
+        anyhow::bail!("😿 Speed must be greater than 0.")
+    }
+    if idle_time_limit.is_some_and(|limit| !valid_limit(limit)) {
+        anyhow::bail!("😿 Idle time limit must be greater than 0.")
+    }
+
+    let file =
+        File::open(path).map_err(|e| anyhow::anyhow!("😿 Failed to open {}: {}", path, e))?;
+    let mut lines = BufReader::new(file).lines();
+
+    let header: Header = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)
+            .map_err(|e| anyhow::anyhow!("😿 {} is not an asciicast file: {}", path, e))?,
+        None => anyhow::bail!("😿 {} is empty.", path),
+    };
+    if header.version != 2 {
+        anyhow::bail!("😿 Unsupported asciicast version {}.", header.version)
+    }
+    if header
+        .idle_time_limit
+        .is_some_and(|limit| !valid_limit(limit))
+    {
+        anyhow::bail!("😿 {} has an invalid idle_time_limit.", path)
+    }
+    let idle_time_limit = idle_time_limit.or(header.idle_time_limit);
+
+    let mut stdout = tokio::io::stdout();
+    let mut last = 0.0;
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (time, code, data): (f64, String, String) = serde_json::from_str(&line)?;
+
+        let mut delay = (time - last).max(0.0);
+        if let Some(limit) = idle_time_limit {
+            delay = delay.min(limit);
+        }
+        last = time;
+        let delay = Duration::try_from_secs_f64(delay / speed)
+            .map_err(|_| anyhow::anyhow!("😿 {} has an invalid event time {}.", path, time))?;
+        tokio::time::sleep(delay).await;
+
+        // Resizes can't be applied to the local terminal, only output is shown
+        if code == "o" {
+            stdout.write_all(data.as_bytes()).await?;
+            stdout.flush().await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn valid_limit(limit: f64) -> bool {
+    limit.is_finite() && limit > 0.0
+}
@@ -0,0 +1,163 @@
+use crate::sftp::{Sftp, base_name, remote_path};
+use russh_sftp::protocol::{FileAttributes, OpenFlags};
+use std::{fs, path::Path};
+use tokio::io::AsyncWriteExt;
+
+/// How a remote edit ended.
+pub(crate) enum Outcome {
+    Unchanged,
+    Uploaded(u64),
+}
+
+/// Downloads `path` to a temporary file, opens it in the user's editor and
+/// uploads the result if it changed. A file that doesn't exist yet starts
+/// out empty.
+pub(crate) async fn run(sftp: &Sftp, path: &str) -> anyhow::Result<Outcome> {
+    let remote = remote_path(path);
+    let before = sftp.sftp.metadata(remote.as_str()).await.ok();
+    if before.as_ref().is_some_and(|m| !m.file_type().is_file()) {
+        anyhow::bail!("😿 {} is not a regular file.", remote)
+    }
+
+    let original = match before {
+        Some(_) => sftp
+            .sftp
+            .read(remote.as_str())
+            .await
+            .map_err(|e| anyhow::anyhow!("😿 {}: {}", remote, e))?,
+        None => vec![],
+    };
+
+    // Keep the file name, so that editors pick the right syntax
+    let temp = tempfile::Builder::new()
+        .prefix("ssher-")
+        .suffix(&format!("-{}", base_name(&remote)))
+        .tempfile()?;
+    fs::write(temp.path(), &original)?;
+
+    open_editor(temp.path()).await?;
+
+    let edited = fs::read(temp.path())?;
+    if edited == original {
+        return Ok(Outcome::Unchanged);
+    }
+
+    let after = sftp.sftp.metadata(remote.as_str()).await.ok();
+    if changed(before.as_ref(), after.as_ref()) {
+        let (_, kept) = temp.keep()?;
+        anyhow::bail!(
+            "😿 {} changed on the server while you were editing, your copy is kept at {}.",
+            remote,
+            kept.display()
+        )
+    }
+
+    upload(sftp, &remote, &edited, before.as_ref()).await?;
+    Ok(Outcome::Uploaded(edited.len() as u64))
+}
+
+fn changed(before: Option<&FileAttributes>, after: Option<&FileAttributes>) -> bool {
+    match (before, after) {
+        (Some(before), Some(after)) => before.mtime != after.mtime || before.size != after.size,
+        (None, None) => false,
+        _ => true,
+    }
+}
+
+async fn open_editor(path: &Path) -> anyhow::Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .ok()
+        .filter(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| {
+            if cfg!(windows) {
+                "notepad".to_string()
+            } else {
+                "vi".to_string()
+            }
+        });
+
+    // Through the shell like git does, so the editor may carry quoted arguments
+    #[cfg(unix)]
+    let mut command = {
+        let mut command = tokio::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("{} \"$@\"", editor))
+            .arg(&editor);
+        command
+    };
+    #[cfg(not(unix))]
+    let mut command = {
+        let mut words = editor.split_whitespace();
+        let mut command = tokio::process::Command::new(words.next().unwrap_or_default());
+        command.args(words);
+        command
+    };
+
+    let status = command
+        .arg(path)
+        .status()
+        .await
+        .map_err(|e| anyhow::anyhow!("😿 Failed to start {}: {}", editor, e))?;
+    if !status.success() {
+        anyhow::bail!("😿 {} exited with {}, nothing uploaded.", editor, status)
+    }
+    Ok(())
+}
+
+/// Writes `data` next to `remote` and renames it into place, so the file is
+/// never left half written.
+async fn upload(
+    sftp: &Sftp,
+    remote: &str,
+    data: &[u8],
+    metadata: Option<&FileAttributes>,
+) -> anyhow::Result<()> {
+    let (dir, name) = match remote.rfind('/') {
+        Some(i) => remote.split_at(i + 1),
+        None => ("", remote),
+    };
+    let staging = format!("{}.{}.ssher-{}", dir, name, std::process::id());
+
+    let mut file = sftp
+        .sftp
+        .open_with_flags(
+            staging.as_str(),
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("😿 {}: {}", staging, e))?;
+    file.write_all(data).await?;
+    file.shutdown().await?;
+    drop(file);
+
+    if let Some(permissions) = metadata.and_then(|m| m.permissions) {
+        let mut attributes = FileAttributes::empty();
+        attributes.permissions = Some(permissions & 0o7777);
+        sftp.sftp.set_metadata(staging.as_str(), attributes).await?;
+    }
+
+    if sftp.sftp.rename(staging.as_str(), remote).await.is_ok() {
+        return Ok(());
+    }
+
+    // Plain sftp renames don't replace existing files, so move the old one
+    // out of the way first and put it back if that fails too
+    let backup = format!("{}.{}.ssher-old-{}", dir, name, std::process::id());
+    let result = async {
+        sftp.sftp.rename(remote, backup.as_str()).await?;
+        if let Err(e) = sftp.sftp.rename(staging.as_str(), remote).await {
+            let _ = sftp.sftp.rename(backup.as_str(), remote).await;
+            return Err(e);
+        }
+        sftp.sftp.remove_file(backup.as_str()).await
+    }
+    .await;
+
+    if let Err(e) = result {
+        let _ = sftp.sftp.remove_file(staging.as_str()).await;
+        anyhow::bail!("😿 Failed to replace {}: {}", remote, e)
+    }
+    Ok(())
+}
@@ -0,0 +1,390 @@
+use crate::{
+    model::Server,
+    ssh::{ChannelRequest, Session},
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use russh_sftp::{
+    client::SftpSession,
+    protocol::{FileAttributes, OpenFlags},
+};
+use std::{
+    fs::{self, FileTimes},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct TransferOptions {
+    pub(crate) recursive: bool,
+    /// Keep permissions and modification times.
+    pub(crate) preserve: bool,
+    /// Continue partially transferred files instead of starting over.
+    pub(crate) resume: bool,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TransferStats {
+    pub(crate) files: usize,
+    pub(crate) bytes: u64,
+}
+
+/// An SFTP session over a connected server.
+pub(crate) struct Sftp {
+    pub(crate) session: Session,
+    pub(crate) sftp: SftpSession,
+}
+
+impl Sftp {
+    pub(crate) async fn connect(server: Server) -> anyhow::Result<Self> {
+        let session = Session::connect(server).await?;
+        let request = ChannelRequest {
+            subsystem: Some("sftp".to_string()),
+            ..Default::default()
+        };
+        let channel = session.open_channel(&request).await?;
+        let sftp = SftpSession::new(channel.into_stream())
+            .await
+            .map_err(|e| anyhow::anyhow!("😿 Failed to start sftp: {}", e))?;
+
+        Ok(Self { session, sftp })
+    }
+
+    pub(crate) async fn close(self) -> anyhow::Result<()> {
+        let _ = self.sftp.close().await;
+        self.session.close().await
+    }
+
+    /// Copies `remote` to `local`, into it when `local` is a directory.
+    pub(crate) async fn download(
+        &self,
+        remote: &str,
+        local: &Path,
+        options: TransferOptions,
+    ) -> anyhow::Result<TransferStats> {
+        let remote = remote_path(remote);
+        let metadata = self
+            .sftp
+            .metadata(remote.as_str())
+            .await
+            .map_err(|e| anyhow::anyhow!("😿 {}: {}", remote, e))?;
+
+        let target = if local.is_dir() {
+            local.join(base_name(&remote))
+        } else {
+            local.to_path_buf()
+        };
+
+        let mut stats = TransferStats::default();
+        if metadata.file_type().is_dir() {
+            if !options.recursive {
+                anyhow::bail!("😿 {} is a directory, use -r to copy it.", remote)
+            }
+            self.download_dir(&remote, &target, &metadata, options, &mut stats)
+                .await?;
+        } else {
+            self.download_file(&remote, &target, &metadata, options, &mut stats)
+                .await?;
+        }
+
+        Ok(stats)
+    }
+
+    async fn download_dir(
+        &self,
+        remote: &str,
+        local: &Path,
+        metadata: &FileAttributes,
+        options: TransferOptions,
+        stats: &mut TransferStats,
+    ) -> anyhow::Result<()> {
+        fs::create_dir_all(local)?;
+
+        for entry in self.sftp.read_dir(remote).await? {
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = join_remote(remote, &name);
+            let mut metadata = entry.metadata();
+            if metadata.file_type().is_symlink() {
+                metadata = self.sftp.metadata(path.as_str()).await?;
+            }
+
+            if metadata.file_type().is_dir() {
+                Box::pin(self.download_dir(&path, &local.join(&name), &metadata, options, stats))
+                    .await?;
+            } else if metadata.file_type().is_file() {
+                self.download_file(&path, &local.join(&name), &metadata, options, stats)
+                    .await?;
+            }
+        }
+
+        if options.preserve {
+            preserve_local(local, metadata)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn download_file(
+        &self,
+        remote: &str,
+        local: &Path,
+        metadata: &FileAttributes,
+        options: TransferOptions,
+        stats: &mut TransferStats,
+    ) -> anyhow::Result<()> {
+        let size = metadata.size.unwrap_or(0);
+        let offset = match fs::metadata(local) {
+            Ok(existing) if options.resume && existing.len() <= size => existing.len(),
+            _ => 0,
+        };
+
+        let mut source = self.sftp.open(remote).await?;
+        source.seek(std::io::SeekFrom::Start(offset)).await?;
+
+        let mut target = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(offset == 0)
+            .open(local)
+            .await
+            .map_err(|e| anyhow::anyhow!("😿 {}: {}", local.display(), e))?;
+        target.seek(std::io::SeekFrom::Start(offset)).await?;
+
+        let bytes = copy(&mut source, &mut target, remote, offset, size).await?;
+        target.flush().await?;
+        drop(target);
+
+        if options.preserve {
+            preserve_local(local, metadata)?;
+        }
+        stats.files += 1;
+        stats.bytes += bytes;
+        Ok(())
+    }
+
+    /// Copies `local` to `remote`, into it when `remote` is a directory.
+    pub(crate) async fn upload(
+        &self,
+        local: &Path,
+        remote: &str,
+        options: TransferOptions,
+    ) -> anyhow::Result<TransferStats> {
+        let remote = remote_path(remote);
+        let metadata =
+            fs::metadata(local).map_err(|e| anyhow::anyhow!("😿 {}: {}", local.display(), e))?;
+
+        let target = match self.sftp.metadata(remote.as_str()).await {
+            Ok(existing) if existing.file_type().is_dir() => {
+                let name = local
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                join_remote(&remote, &name)
+            }
+            _ => remote,
+        };
+
+        let mut stats = TransferStats::default();
+        if metadata.is_dir() {
+            if !options.recursive {
+                anyhow::bail!("😿 {} is a directory, use -r to copy it.", local.display())
+            }
+            self.upload_dir(local, &target, &metadata, options, &mut stats)
+                .await?;
+        } else {
+            self.upload_file(local, &target, &metadata, options, &mut stats)
+                .await?;
+        }
+
+        Ok(stats)
+    }
+
+    async fn upload_dir(
+        &self,
+        local: &Path,
+        remote: &str,
+        metadata: &fs::Metadata,
+        options: TransferOptions,
+        stats: &mut TransferStats,
+    ) -> anyhow::Result<()> {
+        if !self.sftp.try_exists(remote).await? {
+            self.sftp.create_dir(remote).await?;
+        }
+
+        let mut entries: Vec<PathBuf> = fs::read_dir(local)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()?;
+        entries.sort();
+
+        for path in entries {
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let target = join_remote(remote, &name);
+            // Follows symlinks, like scp does
+            let metadata = fs::metadata(&path)?;
+
+            if metadata.is_dir() {
+                Box::pin(self.upload_dir(&path, &target, &metadata, options, stats)).await?;
+            } else if metadata.is_file() {
+                self.upload_file(&path, &target, &metadata, options, stats)
+                    .await?;
+            }
+        }
+
+        if options.preserve {
+            self.preserve_remote(remote, metadata).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn upload_file(
+        &self,
+        local: &Path,
+        remote: &str,
+        metadata: &fs::Metadata,
+        options: TransferOptions,
+        stats: &mut TransferStats,
+    ) -> anyhow::Result<()> {
+        let size = metadata.len();
+        let offset = match self.sftp.metadata(remote).await {
+            Ok(existing) if options.resume && existing.size.unwrap_or(0) <= size => {
+                existing.size.unwrap_or(0)
+            }
+            _ => 0,
+        };
+
+        let mut source = tokio::fs::File::open(local).await?;
+        source.seek(std::io::SeekFrom::Start(offset)).await?;
+
+        let mut flags = OpenFlags::WRITE | OpenFlags::CREATE;
+        if offset == 0 {
+            flags |= OpenFlags::TRUNCATE;
+        }
+        let mut target = self
+            .sftp
+            .open_with_flags(remote, flags)
+            .await
+            .map_err(|e| anyhow::anyhow!("😿 {}: {}", remote, e))?;
+        target.seek(std::io::SeekFrom::Start(offset)).await?;
+
+        let name = local.display().to_string();
+        let bytes = copy(&mut source, &mut target, &name, offset, size).await?;
+        target.shutdown().await?;
+        drop(target);
+
+        if options.preserve {
+            self.preserve_remote(remote, metadata).await?;
+        }
+        stats.files += 1;
+        stats.bytes += bytes;
+        Ok(())
+    }
+
+    async fn preserve_remote(&self, remote: &str, metadata: &fs::Metadata) -> anyhow::Result<()> {
+        let mut attributes = FileAttributes::empty();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            attributes.permissions = Some(metadata.permissions().mode() & 0o7777);
+        }
+        let seconds = |time: std::io::Result<SystemTime>| {
+            time.ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs() as u32)
+        };
+        attributes.mtime = seconds(metadata.modified());
+        attributes.atime = seconds(metadata.accessed()).or(attributes.mtime);
+
+        self.sftp.set_metadata(remote, attributes).await?;
+        Ok(())
+    }
+}
+
+/// SFTP paths are relative to the home directory, so `~/` is dropped.
+pub(crate) fn remote_path(path: &str) -> String {
+    match path {
+        "" | "~" => ".".to_string(),
+        _ => path.strip_prefix("~/").unwrap_or(path).to_string(),
+    }
+}
+
+pub(crate) fn join_remote(dir: &str, name: &str) -> String {
+    match dir {
+        "" | "." => name.to_string(),
+        "/" => format!("/{}", name),
+        _ => format!("{}/{}", dir.trim_end_matches('/'), name),
+    }
+}
+
+pub(crate) fn base_name(path: &str) -> &str {
+    let trimmed = path.trim_end_matches('/');
+    match trimmed.rsplit('/').next() {
+        Some("") | Some(".") | None => ".",
+        Some(name) => name,
+    }
+}
+
+fn preserve_local(local: &Path, metadata: &FileAttributes) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    if let Some(mode) = metadata.permissions {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(local, fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    if let Some(mtime) = metadata.mtime {
+        let time = |seconds: u32| UNIX_EPOCH + Duration::from_secs(seconds as u64);
+        let times = FileTimes::new()
+            .set_modified(time(mtime))
+            .set_accessed(time(metadata.atime.unwrap_or(mtime)));
+        // Directories can't be opened for writing, reading is enough to
+        // change their times on unix
+        let file = if local.is_dir() {
+            fs::File::open(local)
+        } else {
+            fs::OpenOptions::new().write(true).open(local)
+        };
+        if let Ok(file) = file {
+            file.set_times(times)?;
+        }
+    }
+    Ok(())
+}
+
+async fn copy<R, W>(
+    source: &mut R,
+    target: &mut W,
+    name: &str,
+    offset: u64,
+    size: u64,
+) -> anyhow::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let bar = ProgressBar::new(size).with_position(offset);
+    bar.set_style(
+        ProgressStyle::with_template(
+            "{msg:30!} {bytes:>10}/{total_bytes:<10} [{bar:30.cyan/blue}] {bytes_per_sec:>12} {eta:>4}",
+        )?
+        .progress_chars("=> "),
+    );
+    bar.set_message(name.to_string());
+
+    let mut buf = vec![0; 32 * 1024];
+    let mut copied = 0;
+    loop {
+        let n = source.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        target.write_all(&buf[..n]).await?;
+        copied += n as u64;
+        bar.inc(n as u64);
+    }
+    bar.finish();
+
+    Ok(copied)
+}
@@ -0,0 +1,409 @@
+use crate::{
+    colord_print::{green, yellow},
+    prompt::default_theme,
+    sftp::{Sftp, TransferOptions, join_remote},
+};
+use console::style;
+use dialoguer::{BasicHistory, Completion, Input};
+use indicatif::HumanBytes;
+use russh_sftp::protocol::{FileAttributes, FileType};
+use std::{fs, path::PathBuf};
+use tokio::runtime::Handle;
+
+const COMMANDS: [&str; 14] = [
+    "cd", "chmod", "exit", "get", "help", "lcd", "lls", "lpwd", "ls", "mkdir", "put", "pwd",
+    "quit", "rm",
+];
+
+const HELP: &str = "\
+cd <dir>                      change the remote directory
+lcd <dir>                     change the local directory
+pwd, lpwd                     print the remote / local directory
+ls [path], lls [path]         list a remote / local directory
+get [-r] [-p] [-c] <remote> [local]
+put [-r] [-p] [-c] <local> [remote]
+                              transfer files, -r directories, -p preserve
+                              permissions and times, -c resume
+rm <path>                     remove a remote file or empty directory
+mkdir <dir>                   create a remote directory
+chmod <mode> <path>           change remote permissions, e.g. chmod 644 a.txt
+exit, quit                    leave the shell";
+
+struct Shell<'a> {
+    sftp: &'a Sftp,
+    name: String,
+    home: String,
+    remote_dir: String,
+    local_dir: PathBuf,
+}
+
+/// Runs an interactive file browser until `exit`, remote paths are
+/// completed with Tab.
+pub(crate) async fn run(sftp: &Sftp, name: &str) -> anyhow::Result<()> {
+    let home = sftp.sftp.canonicalize(".").await?;
+    let mut shell = Shell {
+        sftp,
+        name: name.to_string(),
+        remote_dir: home.clone(),
+        home,
+        local_dir: std::env::current_dir()?,
+    };
+    let mut history = BasicHistory::new().max_entries(100).no_duplicates(true);
+
+    green("😺 Type help for the list of commands.");
+    loop {
+        let prompt = format!("sftp {}:{}", shell.name, shell.remote_dir);
+        // The completion reads remote directories, which needs to block
+        let line = tokio::task::block_in_place(|| {
+            Input::<String>::with_theme(&default_theme())
+                .with_prompt(prompt)
+                .allow_empty(true)
+                .history_with(&mut history)
+                .completion_with(&shell)
+                .interact_text()
+        });
+        let Ok(line) = line else {
+            break;
+        };
+
+        let args = split_args(&line);
+        let Some((command, args)) = args.split_first() else {
+            continue;
+        };
+        if matches!(command.as_str(), "exit" | "quit") {
+            break;
+        }
+        if let Err(e) = shell.execute(command, args).await {
+            yellow(format!("😿 {}", e.to_string().trim_start_matches("😿 ")));
+        }
+    }
+
+    Ok(())
+}
+
+impl Shell<'_> {
+    async fn execute(&mut self, command: &str, args: &[String]) -> anyhow::Result<()> {
+        match command {
+            "help" => println!("{}", HELP),
+            "pwd" => println!("{}", self.remote_dir),
+            "lpwd" => println!("{}", self.local_dir.display()),
+            "cd" => {
+                let dir = self.remote(args.first().map(String::as_str).unwrap_or("~"));
+                let dir = self.sftp.sftp.canonicalize(dir).await?;
+                if !self
+                    .sftp
+                    .sftp
+                    .metadata(dir.as_str())
+                    .await?
+                    .file_type()
+                    .is_dir()
+                {
+                    anyhow::bail!("{} is not a directory.", dir)
+                }
+                self.remote_dir = dir;
+            }
+            "lcd" => {
+                let dir = match args.first() {
+                    Some(dir) => self.local(dir),
+                    None => PathBuf::from(shellexpand::tilde("~").into_owned()),
+                };
+                self.local_dir = dir.canonicalize()?;
+            }
+            "ls" => {
+                let dir = self.remote(args.first().map(String::as_str).unwrap_or("."));
+                let mut entries: Vec<(String, FileAttributes)> = self
+                    .sftp
+                    .sftp
+                    .read_dir(dir)
+                    .await?
+                    .map(|entry| (entry.file_name(), entry.metadata()))
+                    .filter(|(name, _)| name != "." && name != "..")
+                    .collect();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                for (name, attributes) in entries {
+                    println!("{}", list_line(&name, &attributes));
+                }
+            }
+            "lls" => {
+                let dir = self.local(args.first().map(String::as_str).unwrap_or("."));
+                let mut entries: Vec<(String, FileAttributes)> = fs::read_dir(dir)?
+                    .filter_map(|entry| entry.ok())
+                    .filter_map(|entry| {
+                        let metadata = entry.metadata().ok()?;
+                        let name = entry.file_name().to_string_lossy().into_owned();
+                        Some((name, FileAttributes::from(&metadata)))
+                    })
+                    .collect();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                for (name, attributes) in entries {
+                    println!("{}", list_line(&name, &attributes));
+                }
+            }
+            "get" => {
+                let (options, paths) = transfer_args(args);
+                let Some(remote) = paths.first() else {
+                    anyhow::bail!("Usage: get [-r] [-p] [-c] <remote> [local]")
+                };
+                let remote = self.remote(remote);
+                let local = self.local(paths.get(1).map(String::as_str).unwrap_or("."));
+                let stats = self.sftp.download(&remote, &local, options).await?;
+                green(format!(
+                    "😺 Downloaded {} files, {}.",
+                    stats.files,
+                    HumanBytes(stats.bytes)
+                ));
+            }
+            "put" => {
+                let (options, paths) = transfer_args(args);
+                let Some(local) = paths.first() else {
+                    anyhow::bail!("Usage: put [-r] [-p] [-c] <local> [remote]")
+                };
+                let local = self.local(local);
+                let remote = self.remote(paths.get(1).map(String::as_str).unwrap_or("."));
+                let stats = self.sftp.upload(&local, &remote, options).await?;
+                green(format!(
+                    "😺 Uploaded {} files, {}.",
+                    stats.files,
+                    HumanBytes(stats.bytes)
+                ));
+            }
+            "rm" => {
+                if args.is_empty() {
+                    anyhow::bail!("Usage: rm <path>...")
+                }
+                for path in args {
+                    let path = self.remote(path);
+                    let metadata = self.sftp.sftp.symlink_metadata(path.as_str()).await?;
+                    if metadata.file_type().is_dir() {
+                        self.sftp.sftp.remove_dir(path).await?;
+                    } else {
+                        self.sftp.sftp.remove_file(path).await?;
+                    }
+                }
+            }
+            "mkdir" => {
+                if args.is_empty() {
+                    anyhow::bail!("Usage: mkdir <dir>...")
+                }
+                for dir in args {
+                    self.sftp.sftp.create_dir(self.remote(dir)).await?;
+                }
+            }
+            "chmod" => {
+                let (Some(mode), Some(_)) = (args.first(), args.get(1)) else {
+                    anyhow::bail!("Usage: chmod <mode> <path>...")
+                };
+                let mode = u32::from_str_radix(mode, 8)
+                    .map_err(|_| anyhow::anyhow!("{} is not an octal mode.", mode))?;
+                for path in &args[1..] {
+                    let mut attributes = FileAttributes::empty();
+                    attributes.permissions = Some(mode);
+                    self.sftp
+                        .sftp
+                        .set_metadata(self.remote(path), attributes)
+                        .await?;
+                }
+            }
+            _ => anyhow::bail!("Unknown command {}, type help for the list.", command),
+        }
+
+        Ok(())
+    }
+
+    fn remote(&self, path: &str) -> String {
+        if path == "~" {
+            self.home.clone()
+        } else if let Some(rest) = path.strip_prefix("~/") {
+            join_remote(&self.home, rest)
+        } else if path.starts_with('/') {
+            path.to_string()
+        } else {
+            join_remote(&self.remote_dir, path)
+        }
+    }
+
+    fn local(&self, path: &str) -> PathBuf {
+        self.local_dir.join(shellexpand::tilde(path).as_ref())
+    }
+
+    /// Names in a remote directory, directories end with `/`.
+    fn remote_names(&self, dir: &str) -> Vec<String> {
+        let dir = self.remote(if dir.is_empty() { "." } else { dir });
+        Handle::current()
+            .block_on(self.sftp.sftp.read_dir(dir))
+            .map(|entries| {
+                entries
+                    .filter(|entry| entry.file_name() != "." && entry.file_name() != "..")
+                    .map(|entry| {
+                        let mut name = entry.file_name();
+                        if entry.file_type().is_dir() {
+                            name.push('/');
+                        }
+                        name
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn local_names(&self, dir: &str) -> Vec<String> {
+        fs::read_dir(self.local(if dir.is_empty() { "." } else { dir }))
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| {
+                        let mut name = entry.file_name().to_string_lossy().into_owned();
+                        if entry.path().is_dir() {
+                            name.push('/');
+                        }
+                        name
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl Completion for Shell<'_> {
+    fn get(&self, input: &str) -> Option<String> {
+        let (head, word) = match input.rfind(' ') {
+            Some(i) => input.split_at(i + 1),
+            None => ("", input),
+        };
+        let args: Vec<&str> = head
+            .split_whitespace()
+            .filter(|arg| !arg.starts_with('-'))
+            .collect();
+
+        // Complete within the directory part of the word typed so far
+        let dir = match word.rsplit_once('/') {
+            Some(("", _)) => "/",
+            Some((dir, _)) => dir,
+            None => "",
+        };
+        let prefix = match dir {
+            "" => String::new(),
+            "/" => "/".to_string(),
+            _ => format!("{}/", dir),
+        };
+        let candidates: Vec<String> = match args.as_slice() {
+            [] => COMMANDS.iter().map(|c| format!("{} ", c)).collect(),
+            ["lcd" | "lls"] | ["put"] | ["get", _] => self.local_names(dir),
+            [_, ..] => self.remote_names(dir),
+        };
+        let candidates: Vec<String> = match args.as_slice() {
+            [] => candidates,
+            _ => candidates
+                .into_iter()
+                .map(|name| format!("{}{}", prefix, name))
+                .collect(),
+        };
+
+        let matches: Vec<&String> = candidates.iter().filter(|c| c.starts_with(word)).collect();
+        let completed = match matches.as_slice() {
+            [] => return None,
+            [only] => only.to_string(),
+            [first, rest @ ..] => rest.iter().fold(first.to_string(), |common, c| {
+                common
+                    .chars()
+                    .zip(c.chars())
+                    .take_while(|(a, b)| a == b)
+                    .map(|(a, _)| a)
+                    .collect()
+            }),
+        };
+        Some(format!("{}{}", head, completed))
+    }
+}
+
+fn transfer_args(args: &[String]) -> (TransferOptions, Vec<String>) {
+    let mut options = TransferOptions::default();
+    let mut paths = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "-r" => options.recursive = true,
+            "-p" => options.preserve = true,
+            "-c" => options.resume = true,
+            _ => paths.push(arg.clone()),
+        }
+    }
+    (options, paths)
+}
+
+fn list_line(name: &str, attributes: &FileAttributes) -> String {
+    let kind = match attributes.file_type() {
+        FileType::Dir => 'd',
+        FileType::Symlink => 'l',
+        FileType::File => '-',
+        FileType::Other => '?',
+    };
+    let mode = attributes.permissions.unwrap_or(0);
+    let permissions: String = (0..9)
+        .map(|i| {
+            if mode & (0o400 >> i) == 0 {
+                '-'
+            } else {
+                ['r', 'w', 'x'][i % 3]
+            }
+        })
+        .collect();
+    let modified = attributes
+        .mtime
+        .and_then(|mtime| chrono::DateTime::from_timestamp(mtime as i64, 0))
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_default();
+    let name = match attributes.file_type() {
+        FileType::Dir => style(format!("{}/", name)).blue().to_string(),
+        _ => name.to_string(),
+    };
+
+    format!(
+        "{}{} {:>10} {:>16} {}",
+        kind,
+        permissions,
+        HumanBytes(attributes.size.unwrap_or(0)).to_string(),
+        modified,
+        name
+    )
+}
+
+/// Splits a command line on whitespace, honouring quotes and backslashes.
+fn split_args(line: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None | Some('"'), '\\') => {
+                current.extend(chars.next());
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    args.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            (_, c) => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        args.push(current);
+    }
+    args
+}
@@ -1,30 +1,56 @@
-use crate::colord_print::red;
+#[cfg(unix)]
+use crate::colord_print::yellow;
+use crate::colord_print::{green, red};
+use crate::common::wildcard_match;
 use crate::endec;
 use crate::model::Server;
+use crate::prompt::password_prompt;
+use crate::record::Recorder;
+use crate::transcript::Transcript;
+#[cfg(unix)]
+use crate::{forward, mux};
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode, is_raw_mode_enabled, size};
 use russh::keys::*;
 use russh::*;
+use serde::{Deserialize, Serialize};
 use std::env;
+use std::io::IsTerminal;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
 use trust_dns_resolver::AsyncResolver;
 use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
 
-pub async fn exec(server: Server) -> anyhow::Result<()> {
+/// Runs an interactive session, returning the server as connected, i.e.
+/// with the password typed at the prompt if there was one.
+pub async fn exec(server: Server) -> anyhow::Result<Server> {
     let mut ssh = Session::connect(server).await?;
 
     ssh.shell().await?;
     ssh.close().await?;
 
-    Ok(())
+    Ok(ssh.server)
 }
 
-struct Client {}
+static PASSWORD_PROMPT: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Forwarding state shared by a connection's handler and its channels,
+/// channels opened by the server are refused until one of ours asked for them.
+#[derive(Default)]
+pub(crate) struct Forwarding {
+    agent: AtomicBool,
+    #[cfg(unix)]
+    x11: std::sync::Mutex<Option<forward::X11Forward>>,
+}
+
+pub(crate) struct Client {
+    forwarding: Arc<Forwarding>,
+}
 
 impl Client {
-    fn new() -> Self {
-        Self {}
+    fn new(forwarding: Arc<Forwarding>) -> Self {
+        Self { forwarding }
     }
 }
 
@@ -37,14 +63,123 @@ impl client::Handler for Client {
     ) -> anyhow::Result<bool, Self::Error> {
         Ok(true)
     }
+
+    async fn server_channel_open_agent_forward(
+        &mut self,
+        channel: Channel<client::Msg>,
+        _session: &mut client::Session,
+    ) -> anyhow::Result<(), Self::Error> {
+        if !self.forwarding.agent.load(Ordering::SeqCst) {
+            return channel.close().await;
+        }
+
+        #[cfg(unix)]
+        tokio::spawn(forward::proxy_agent(channel));
+        #[cfg(not(unix))]
+        channel.close().await?;
+        Ok(())
+    }
+
+    async fn server_channel_open_x11(
+        &mut self,
+        channel: Channel<client::Msg>,
+        _originator_address: &str,
+        _originator_port: u32,
+        _session: &mut client::Session,
+    ) -> anyhow::Result<(), Self::Error> {
+        #[cfg(unix)]
+        if let Some(x11) = self.forwarding.x11.lock().ok().and_then(|x11| x11.clone()) {
+            tokio::spawn(forward::proxy_x11(channel, x11));
+            return Ok(());
+        }
+
+        channel.close().await
+    }
+}
+
+/// Local variables matching `send_env` followed by the fixed `set_env` pairs,
+/// the latter win when a name appears in both.
+fn environment(server: &Server) -> Vec<(String, String)> {
+    let mut env: Vec<(String, String)> = env::vars()
+        .filter(|(name, _)| {
+            !server.set_env.contains_key(name)
+                && server
+                    .send_env
+                    .iter()
+                    .any(|pattern| wildcard_match(pattern, name))
+        })
+        .collect();
+    env.extend(server.set_env.clone());
+    env
+}
+
+fn remote_cd(server: &Server) -> Option<String> {
+    server
+        .remote_dir
+        .as_ref()
+        .map(|dir| match dir.strip_prefix("~/") {
+            _ if dir == "~" => "cd ~".to_string(),
+            Some(rest) => format!("cd ~/{}", shell_quote(rest)),
+            None => format!("cd {}", shell_quote(dir)),
+        })
+}
+
+/// Combines `remote_dir` and `remote_command` into what runs on connect,
+/// `None` leaves the server to start the login shell.
+fn startup_command(server: &Server) -> Option<String> {
+    match (remote_cd(server), &server.remote_command) {
+        (Some(cd), Some(command)) => Some(format!("{} && {}", cd, command)),
+        (Some(cd), None) => Some(format!("{} && exec $SHELL -l", cd)),
+        (None, Some(command)) => Some(command.clone()),
+        (None, None) => None,
+    }
 }
 
-struct RawModeGuard {
+/// The channel setup for an interactive session on a `col` x `row` terminal.
+pub(crate) fn shell_request(server: &Server, col: u16, row: u16) -> ChannelRequest {
+    ChannelRequest {
+        pty: Some(PtyRequest {
+            term: env::var("TERM").unwrap_or("xterm".into()),
+            col: col as u32,
+            row: row as u32,
+        }),
+        forward_agent: server.forward_agent.unwrap_or(false),
+        x11: if server.forward_x11_trusted.unwrap_or(false) {
+            Some(X11Request { trusted: true })
+        } else if server.forward_x11.unwrap_or(false) {
+            Some(X11Request { trusted: false })
+        } else {
+            None
+        },
+        env: environment(server),
+        command: startup_command(server),
+        subsystem: None,
+    }
+}
+
+/// The channel setup for running `command` without a terminal, from
+/// `remote_dir` when one is set.
+pub(crate) fn exec_request(server: &Server, command: &str) -> ChannelRequest {
+    ChannelRequest {
+        env: environment(server),
+        command: Some(match remote_cd(server) {
+            Some(cd) => format!("{} && {}", cd, command),
+            None => command.to_string(),
+        }),
+        ..Default::default()
+    }
+}
+
+pub(crate) fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+pub(crate) struct RawModeGuard {
     enabled: bool,
 }
 
 impl RawModeGuard {
-    fn new() -> anyhow::Result<Self> {
+    pub(crate) fn new() -> anyhow::Result<Self> {
         let mut enabled = is_raw_mode_enabled()?;
         if !enabled {
             enable_raw_mode()?;
@@ -62,16 +197,222 @@ impl Drop for RawModeGuard {
     }
 }
 
+/// Reads stdin on a helper thread. On unix the thread polls before reading
+/// and stops once dropped, so that no read is left pending to swallow what
+/// is typed into a prompt after the session.
+pub(crate) struct StdinReader {
+    rx: tokio::sync::mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl StdinReader {
+    pub(crate) fn spawn() -> Self {
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let stopped = stop.clone();
+        std::thread::spawn(move || {
+            let mut buf = vec![0; 1024];
+            while !stopped.load(Ordering::SeqCst) {
+                let r = match Self::read_stdin(&mut buf) {
+                    Ok(None) => continue,
+                    Ok(Some(n)) => Ok(buf[..n].to_vec()),
+                    Err(e) => Err(e),
+                };
+                let done = !matches!(r, Ok(ref data) if !data.is_empty());
+                if tx.blocking_send(r).is_err() || done {
+                    break;
+                }
+            }
+        });
+
+        Self { rx, stop }
+    }
+
+    /// Returns `None` when nothing arrived within a short timeout.
+    #[cfg(unix)]
+    fn read_stdin(buf: &mut [u8]) -> std::io::Result<Option<usize>> {
+        let mut fd = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `fd` is a valid pollfd for the duration of the call.
+        if unsafe { libc::poll(&mut fd, 1, 100) } <= 0 {
+            return Ok(None);
+        }
+        // SAFETY: `buf` is valid for writes of `buf.len()` bytes.
+        let n = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) };
+        if n < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Some(n as usize))
+    }
+
+    #[cfg(not(unix))]
+    fn read_stdin(buf: &mut [u8]) -> std::io::Result<Option<usize>> {
+        use std::io::Read;
+        std::io::stdin().read(buf).map(Some)
+    }
+
+    /// Resolves to the next chunk of input, empty on end of file.
+    pub(crate) async fn read(&mut self) -> std::io::Result<Vec<u8>> {
+        self.rx.recv().await.unwrap_or_else(|| Ok(vec![]))
+    }
+}
+
+impl Drop for StdinReader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// What to set up on a freshly opened session channel.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub(crate) struct ChannelRequest {
+    pub(crate) pty: Option<PtyRequest>,
+    #[serde(default)]
+    pub(crate) forward_agent: bool,
+    #[serde(default)]
+    pub(crate) x11: Option<X11Request>,
+    #[serde(default)]
+    pub(crate) env: Vec<(String, String)>,
+    /// Runs this command instead of the login shell.
+    #[serde(default)]
+    pub(crate) command: Option<String>,
+    /// Starts a subsystem such as `sftp`, takes precedence over `command`.
+    #[serde(default)]
+    pub(crate) subsystem: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct X11Request {
+    pub(crate) trusted: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct PtyRequest {
+    pub(crate) term: String,
+    pub(crate) col: u32,
+    pub(crate) row: u32,
+}
+
+/// A session channel, either owned directly or served by a mux master.
+pub(crate) enum RemoteChannel {
+    Direct(Channel<client::Msg>),
+    #[cfg(unix)]
+    Mux(mux::MuxChannel),
+}
+
+impl RemoteChannel {
+    pub(crate) async fn data(&self, data: &[u8]) -> anyhow::Result<()> {
+        match self {
+            Self::Direct(channel) => channel.data(data).await?,
+            #[cfg(unix)]
+            Self::Mux(channel) => channel.data(data)?,
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn eof(&self) -> anyhow::Result<()> {
+        match self {
+            Self::Direct(channel) => channel.eof().await?,
+            #[cfg(unix)]
+            Self::Mux(channel) => channel.eof()?,
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn window_change(&self, col: u32, row: u32) -> anyhow::Result<()> {
+        match self {
+            Self::Direct(channel) => channel.window_change(col, row, 0, 0).await?,
+            #[cfg(unix)]
+            Self::Mux(channel) => channel.window_change(col, row)?,
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn wait(&mut self) -> Option<ChannelMsg> {
+        match self {
+            Self::Direct(channel) => channel.wait().await,
+            #[cfg(unix)]
+            Self::Mux(channel) => channel.wait().await,
+        }
+    }
+
+    /// Turns the channel into a byte stream, e.g. for a subsystem.
+    pub(crate) fn into_stream(mut self) -> DuplexStream {
+        let (stream, mut relay) = tokio::io::duplex(256 * 1024);
+        tokio::spawn(async move {
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                tokio::select! {
+                    r = relay.read(&mut buf) => {
+                        match r {
+                            Ok(0) | Err(_) => {
+                                let _ = self.eof().await;
+                                break;
+                            }
+                            Ok(n) => {
+                                if self.data(&buf[..n]).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                    },
+                    msg = self.wait() => {
+                        let written = match msg {
+                            Some(ChannelMsg::Data { ref data }) => relay.write_all(data).await,
+                            Some(ChannelMsg::Eof) | Some(ChannelMsg::Close) | None => break,
+                            _ => Ok(()),
+                        };
+                        if written.is_err() {
+                            break;
+                        }
+                    },
+                }
+            }
+        });
+        stream
+    }
+}
+
+enum Transport {
+    Direct(client::Handle<Client>),
+    #[cfg(unix)]
+    Mux(mux::MuxClient),
+}
+
 /// This struct is a convenience wrapper
 /// around a russh client
 /// that handles the input/output event loop
 pub struct Session {
-    server_host: String,
-    session: client::Handle<Client>,
+    server: Server,
+    transport: Transport,
+    forwarding: Arc<Forwarding>,
 }
 
 impl Session {
-    async fn connect(server: Server) -> anyhow::Result<Self> {
+    pub(crate) async fn connect(server: Server) -> anyhow::Result<Self> {
+        #[cfg(unix)]
+        if server.control_master.unwrap_or(false) {
+            match mux::connect(&server).await {
+                Ok(client) => {
+                    return Ok(Self {
+                        server,
+                        transport: Transport::Mux(client),
+                        forwarding: Arc::default(),
+                    });
+                }
+                Err(e) => yellow(format!("😿 {}, falling back to a direct connection.", e)),
+            }
+        }
+
+        Self::connect_direct(server).await
+    }
+
+    /// Connects and authenticates without going through a mux master.
+    pub(crate) async fn connect_direct(mut server: Server) -> anyhow::Result<Self> {
         let config = client::Config::default();
         let config = Arc::new(config);
 
@@ -91,19 +432,24 @@ impl Session {
                 .to_string()
         };
 
-        let connect_future = client::connect(config, (host, server.port), Client::new());
+        let forwarding = Arc::new(Forwarding::default());
+        let connect_future =
+            client::connect(config, (host, server.port), Client::new(forwarding.clone()));
         let mut session = match tokio::time::timeout(Duration::from_secs(30), connect_future).await
         {
             Ok(session) => session?,
             Err(_) => anyhow::bail!("Connection to {} timed out after 30s.", server.host.clone()),
         };
 
-        let auth_rs = match server.password {
-            Some(ref password) if !password.is_empty() => {
-                session
-                    .authenticate_password(server.user, endec::decode_string(password)?)
-                    .await?
-            }
+        // Only ask for a password when someone is there to type it, a mux
+        // master keeps failing like before.
+        let interactive = std::io::stdin().is_terminal();
+
+        let mut authenticated = match server.password {
+            Some(ref password) if !password.is_empty() => session
+                .authenticate_password(server.user.clone(), endec::decode_string(password)?)
+                .await?
+                .success(),
             _ => {
                 let identity_file = match server.identity_file {
                     Some(ref identity_file) => identity_file,
@@ -111,58 +457,203 @@ impl Session {
                 };
 
                 let expanded_path = shellexpand::tilde(identity_file).into_owned();
-                let key_pair = load_secret_key(expanded_path, None)?;
-                session
-                    .authenticate_publickey(
-                        server.user,
-                        PrivateKeyWithHashAlg::new(
-                            Arc::new(key_pair),
-                            session.best_supported_rsa_hash().await?.flatten(),
-                        ),
-                    )
-                    .await?
+                let passphrase = match server.passphrase {
+                    Some(ref passphrase) if !passphrase.is_empty() => {
+                        Some(endec::decode_string(passphrase)?)
+                    }
+                    _ => None,
+                };
+                match load_secret_key(expanded_path, passphrase.as_deref()) {
+                    Ok(key_pair) => session
+                        .authenticate_publickey(
+                            server.user.clone(),
+                            PrivateKeyWithHashAlg::new(
+                                Arc::new(key_pair),
+                                session.best_supported_rsa_hash().await?.flatten(),
+                            ),
+                        )
+                        .await?
+                        .success(),
+                    Err(e) if !interactive => return Err(e.into()),
+                    Err(_) => false,
+                }
             }
         };
 
-        if !auth_rs.success() {
+        if !authenticated && interactive && server.password.is_none() {
+            // Several sessions may be connecting at once, ask one at a time
+            // without tying up a worker thread each
+            let password = {
+                let _lock = PASSWORD_PROMPT.lock().await;
+                let prompt = format!("{}@{}'s password:", server.user, server.host);
+                tokio::task::spawn_blocking(move || password_prompt(&prompt)).await??
+            };
+            authenticated = session
+                .authenticate_password(server.user.clone(), password.clone())
+                .await?
+                .success();
+            if authenticated {
+                server.password = endec::encode_string(password);
+            }
+        }
+
+        if !authenticated {
             anyhow::bail!("😿 Authentication failed.")
         }
 
         Ok(Self {
-            server_host: server.host.clone(),
-            session,
+            server,
+            transport: Transport::Direct(session),
+            forwarding,
         })
     }
 
-    async fn shell(&mut self) -> anyhow::Result<()> {
-        // We're using `crossterm` to put the terminal into raw mode, so that we can
-        // display the output of interactive applications correctly
-        let _raw_mode = RawModeGuard::new()?;
+    pub(crate) fn is_closed(&self) -> bool {
+        match &self.transport {
+            Transport::Direct(session) => session.is_closed(),
+            #[cfg(unix)]
+            Transport::Mux(_) => false,
+        }
+    }
+
+    pub(crate) async fn open_channel(
+        &self,
+        request: &ChannelRequest,
+    ) -> anyhow::Result<RemoteChannel> {
+        match &self.transport {
+            Transport::Direct(_) => Ok(RemoteChannel::Direct(self.open_direct(request).await?)),
+            #[cfg(unix)]
+            Transport::Mux(client) => Ok(RemoteChannel::Mux(client.open(request).await?)),
+        }
+    }
 
-        let mut channel = self.session.channel_open_session().await?;
+    /// Runs `command` without a terminal, returning its exit status and
+    /// what it printed to stdout.
+    pub(crate) async fn output(&self, command: &str) -> anyhow::Result<(u32, Vec<u8>)> {
+        let request = ChannelRequest {
+            command: Some(command.to_string()),
+            ..Default::default()
+        };
+        let mut channel = self.open_channel(&request).await?;
+
+        let mut stdout = vec![];
+        let mut code = None;
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::Data { ref data } => stdout.extend_from_slice(data),
+                ChannelMsg::ExitStatus { exit_status } => code = Some(exit_status),
+                _ => {}
+            }
+        }
+
+        let code =
+            code.ok_or_else(|| anyhow::anyhow!("😿 {} exited without a status.", command))?;
+        Ok((code, stdout))
+    }
+
+    /// Opens a channel on the underlying connection, this is what a mux
+    /// master runs on behalf of its clients.
+    pub(crate) async fn open_direct(
+        &self,
+        request: &ChannelRequest,
+    ) -> anyhow::Result<Channel<client::Msg>> {
+        let session = match &self.transport {
+            Transport::Direct(session) => session,
+            #[cfg(unix)]
+            Transport::Mux(_) => anyhow::bail!("Session is not directly connected."),
+        };
+
+        let channel = session.channel_open_session().await?;
+        if let Some(pty) = &request.pty {
+            // Request an interactive PTY from the server
+            channel
+                .request_pty(
+                    true,
+                    &pty.term,
+                    pty.col,
+                    pty.row,
+                    0,
+                    0,
+                    &[], // ideally you want to pass the actual terminal modes here
+                )
+                .await?;
+        }
+        for (name, value) in &request.env {
+            channel
+                .set_env(false, name.as_str(), value.as_str())
+                .await?;
+        }
+        if request.forward_agent {
+            self.forwarding.agent.store(true, Ordering::SeqCst);
+            channel.agent_forward(true).await?;
+        }
+        #[cfg(unix)]
+        if let Some(x11) = &request.x11 {
+            self.request_x11(&channel, x11.trusted).await?;
+        }
+        match (&request.subsystem, &request.command) {
+            (Some(subsystem), _) => channel.request_subsystem(true, subsystem.as_str()).await?,
+            (None, Some(command)) => channel.exec(true, command.as_str()).await?,
+            (None, None) => channel.request_shell(true).await?,
+        }
+
+        Ok(channel)
+    }
+
+    /// Asks for X11 forwarding, all channels of a connection share the same
+    /// cookies. A local display that can't be set up only costs a warning.
+    #[cfg(unix)]
+    async fn request_x11(
+        &self,
+        channel: &Channel<client::Msg>,
+        trusted: bool,
+    ) -> anyhow::Result<()> {
+        let existing = self.forwarding.x11.lock().ok().and_then(|x11| x11.clone());
+        let x11 = match existing {
+            Some(x11) => x11,
+            None => match forward::X11Forward::setup(trusted).await {
+                Ok(x11) => {
+                    if let Ok(mut slot) = self.forwarding.x11.lock() {
+                        *slot = Some(x11.clone());
+                    }
+                    x11
+                }
+                Err(e) => {
+                    yellow(format!("😿 {}", e));
+                    return Ok(());
+                }
+            },
+        };
 
-        let (mut col, mut row) = size()?;
-        // Request an interactive PTY from the server
         channel
-            .request_pty(
+            .request_x11(
                 true,
-                &env::var("TERM").unwrap_or("xterm".into()),
-                col as u32,
-                row as u32,
-                0,
-                0,
-                &[], // ideally you want to pass the actual terminal modes here
+                false,
+                "MIT-MAGIC-COOKIE-1",
+                x11.fake_cookie.clone(),
+                x11.screen,
             )
             .await?;
-        channel.request_shell(true).await?;
+        Ok(())
+    }
+
+    async fn shell(&mut self) -> anyhow::Result<()> {
+        let (mut col, mut row) = size()?;
+        let request = shell_request(&self.server, col, row);
+        let mut transcript = Transcript::open(&self.server)?;
+        let mut channel = self.open_channel(&request).await?;
+        let mut recorder = Recorder::open(&self.server, col, row)?;
 
-        let mut stdin = tokio::io::stdin();
+        // We're using `crossterm` to put the terminal into raw mode, so that we can
+        // display the output of interactive applications correctly
+        let raw_mode = RawModeGuard::new()?;
+
+        let mut stdin = StdinReader::spawn();
         let mut stdout = tokio::io::stdout();
-        let mut buf = vec![0; 1024];
 
         #[cfg(unix)]
         // Spawn a task to handle the SIGTERM signal
-        tokio::spawn(Self::handle_terminate_signal(self.server_host.clone()));
+        tokio::spawn(Self::handle_terminate_signal(self.server.host.clone()));
 
         loop {
             let (new_col, new_row) = size()?;
@@ -170,51 +661,73 @@ impl Session {
                 col = new_col;
                 row = new_row;
                 channel
-                    .window_change(new_col as u32, new_row as u32, 0, 0)
+                    .window_change(new_col as u32, new_row as u32)
                     .await?;
+                if let Some(recorder) = &mut recorder {
+                    recorder.resize(new_col, new_row)?;
+                }
             }
 
             // Handle one of the possible events:
             tokio::select! {
                 // There's terminal input available from the user
-                r = stdin.read(&mut buf)=> {
+                r = stdin.read()=> {
                     match r {
-                        Ok(0) => {
+                        Ok(data) if data.is_empty() => {
                             channel.eof().await?;
                             break;
                         }
                         // Send it to the server
-                        Ok(n) => channel.data(&buf[..n]).await?,
+                        Ok(data) => channel.data(&data).await?,
                         Err(e) => return Err(e.into()),
                     };
                 },
                 // There's an event available on the session channel
-                Some(msg) = channel.wait() => {
+                msg = channel.wait() => {
                     match msg {
                         // Write data to the terminal
-                        ChannelMsg::Data { ref data } => {
+                        Some(ChannelMsg::Data { ref data }) => {
                             stdout.write_all(data).await?;
                             stdout.flush().await?;
+                            if let Some(transcript) = &mut transcript {
+                                transcript.write(data)?;
+                            }
+                            if let Some(recorder) = &mut recorder {
+                                recorder.output(data)?;
+                            }
                         }
                         // The server has closed the channel
-                        ChannelMsg::ExitStatus { .. } =>{
-                            Self::close_connection(self.server_host.clone(), &mut stdout).await?;
+                        Some(ChannelMsg::ExitStatus { .. }) =>{
+                            Self::close_connection(self.server.host.clone(), &mut stdout).await?;
                             channel.eof().await?;
                             break;
                         }
+                        // The channel is gone, e.g. the mux master exited
+                        None => {
+                            Self::close_connection(self.server.host.clone(), &mut stdout).await?;
+                            break;
+                        }
                         _ => {}
                     }
                 },
             }
         }
 
+        drop(raw_mode);
+        if let Some(recorder) = recorder {
+            green(format!("😺 Session recorded to {}.", recorder.path));
+        }
+
         Ok(())
     }
 
-    async fn close(&mut self) -> anyhow::Result<()> {
-        self.session
-            .disconnect(Disconnect::ByApplication, "", "English")
-            .await?;
+    pub(crate) async fn close(&self) -> anyhow::Result<()> {
+        // A mux master owns the connection, leave it open for later clients
+        if let Transport::Direct(session) = &self.transport {
+            session
+                .disconnect(Disconnect::ByApplication, "", "English")
+                .await?;
+        }
         Ok(())
     }
 
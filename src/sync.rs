@@ -0,0 +1,400 @@
+use crate::{
+    common::wildcard_match,
+    sftp::{Sftp, TransferOptions, TransferStats, join_remote},
+    ssh::shell_quote,
+};
+use indicatif::HumanBytes;
+use russh_sftp::protocol::FileAttributes;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::Read,
+    path::Path,
+    time::UNIX_EPOCH,
+};
+use tabled::Tabled;
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SyncOptions {
+    /// Remove files from the target that aren't in the source.
+    pub(crate) delete: bool,
+    pub(crate) dry_run: bool,
+    /// Compare same sized files by sha256 instead of modification time.
+    pub(crate) checksum: bool,
+    pub(crate) include: Vec<String>,
+    pub(crate) exclude: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Entry {
+    is_dir: bool,
+    size: u64,
+    mtime: u32,
+}
+
+#[derive(Debug, Clone, Tabled)]
+#[tabled(rename_all = "UPPERCASE")]
+pub(crate) struct SyncAction {
+    pub(crate) action: &'static str,
+    pub(crate) path: String,
+    #[tabled(display = "display_size")]
+    pub(crate) size: Option<u64>,
+}
+
+fn display_size(value: &Option<u64>) -> String {
+    value
+        .map(|size| HumanBytes(size).to_string())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct SyncSummary {
+    pub(crate) actions: Vec<SyncAction>,
+    pub(crate) unchanged: usize,
+    pub(crate) bytes: u64,
+}
+
+/// Makes the target directory match the source, copying files that are
+/// missing or differ in size or modification time.
+pub(crate) async fn sync(
+    sftp: &Sftp,
+    local: &Path,
+    remote: &str,
+    direction: Direction,
+    options: &SyncOptions,
+) -> anyhow::Result<SyncSummary> {
+    let local_entries = if local.exists() {
+        walk_local(local, options)?
+    } else if direction == Direction::Upload {
+        anyhow::bail!("😿 {} not found.", local.display())
+    } else {
+        BTreeMap::new()
+    };
+    let remote_entries = match sftp.sftp.metadata(remote).await {
+        Ok(_) => walk_remote(sftp, remote, options).await?,
+        Err(_) if direction == Direction::Download => anyhow::bail!("😿 {} not found.", remote),
+        Err(_) => BTreeMap::new(),
+    };
+    let (source, target) = match direction {
+        Direction::Upload => (&local_entries, &remote_entries),
+        Direction::Download => (&remote_entries, &local_entries),
+    };
+
+    let mut summary = SyncSummary::default();
+    let mut same_size = vec![];
+    for (path, entry) in source {
+        match target.get(path) {
+            None if entry.is_dir => summary.actions.push(SyncAction {
+                action: "mkdir",
+                path: path.clone(),
+                size: None,
+            }),
+            None => summary.actions.push(SyncAction {
+                action: "copy",
+                path: path.clone(),
+                size: Some(entry.size),
+            }),
+            Some(existing) if existing.is_dir != entry.is_dir => anyhow::bail!(
+                "😿 {} is a file on one side and a directory on the other.",
+                path
+            ),
+            Some(_) if entry.is_dir => {}
+            Some(existing) if existing.size != entry.size => summary.actions.push(SyncAction {
+                action: "update",
+                path: path.clone(),
+                size: Some(entry.size),
+            }),
+            Some(_) if options.checksum => same_size.push(path.clone()),
+            Some(existing) if existing.mtime != entry.mtime => summary.actions.push(SyncAction {
+                action: "update",
+                path: path.clone(),
+                size: Some(entry.size),
+            }),
+            Some(_) => summary.unchanged += 1,
+        }
+    }
+
+    if !same_size.is_empty() {
+        let local_sums = local_checksums(local, &same_size)?;
+        let remote_sums = remote_checksums(sftp, remote, &same_size).await?;
+        for path in same_size {
+            match (local_sums.get(&path), remote_sums.get(&path)) {
+                (Some(a), Some(b)) if a == b => summary.unchanged += 1,
+                _ => summary.actions.push(SyncAction {
+                    action: "update",
+                    size: source.get(&path).map(|entry| entry.size),
+                    path,
+                }),
+            }
+        }
+    }
+
+    if options.delete {
+        // Deepest first, so that directories are empty by the time they go
+        for (path, entry) in target.iter().rev() {
+            if !source.contains_key(path) {
+                summary.actions.push(SyncAction {
+                    action: "delete",
+                    path: path.clone(),
+                    size: (!entry.is_dir).then_some(entry.size),
+                });
+            }
+        }
+    }
+
+    if options.dry_run {
+        return Ok(summary);
+    }
+
+    if direction == Direction::Upload && remote_entries.is_empty() {
+        sftp.sftp.create_dir(remote).await.ok();
+    }
+    fs::create_dir_all(local).ok();
+
+    let transfer = TransferOptions {
+        preserve: true,
+        ..Default::default()
+    };
+    let mut stats = TransferStats::default();
+    for action in &summary.actions {
+        let local_path = local.join(&action.path);
+        let remote_path = join_remote(remote, &action.path);
+        match (action.action, direction) {
+            ("mkdir", Direction::Upload) => sftp.sftp.create_dir(remote_path).await?,
+            ("mkdir", Direction::Download) => fs::create_dir_all(local_path)?,
+            ("copy" | "update", Direction::Upload) => {
+                let metadata = fs::metadata(&local_path)?;
+                sftp.upload_file(&local_path, &remote_path, &metadata, transfer, &mut stats)
+                    .await?
+            }
+            ("copy" | "update", Direction::Download) => {
+                let metadata = sftp.sftp.metadata(remote_path.as_str()).await?;
+                sftp.download_file(&remote_path, &local_path, &metadata, transfer, &mut stats)
+                    .await?
+            }
+            ("delete", Direction::Upload) => match action.size {
+                Some(_) => sftp.sftp.remove_file(remote_path).await?,
+                None => sftp.sftp.remove_dir(remote_path).await?,
+            },
+            ("delete", Direction::Download) => match action.size {
+                Some(_) => fs::remove_file(local_path)?,
+                None => fs::remove_dir(local_path)?,
+            },
+            _ => unreachable!("unknown sync action {}", action.action),
+        }
+    }
+    summary.bytes = stats.bytes;
+
+    Ok(summary)
+}
+
+/// Whether `path` passes the include and exclude patterns. Patterns with a
+/// `/` match the whole relative path, others match any single component.
+fn selected(path: &str, is_dir: bool, options: &SyncOptions) -> bool {
+    let matches = |pattern: &String| {
+        if pattern.contains('/') {
+            wildcard_match(pattern.trim_start_matches('/'), path)
+        } else {
+            path.split('/').any(|part| wildcard_match(pattern, part))
+        }
+    };
+
+    if options.exclude.iter().any(matches) {
+        return false;
+    }
+    // Directories are always walked, only their files need including
+    is_dir || options.include.is_empty() || options.include.iter().any(matches)
+}
+
+fn walk_local(root: &Path, options: &SyncOptions) -> anyhow::Result<BTreeMap<String, Entry>> {
+    let mut entries = BTreeMap::new();
+    let mut pending = vec![String::new()];
+
+    while let Some(dir) = pending.pop() {
+        for item in fs::read_dir(root.join(&dir))? {
+            let item = item?;
+            let name = item.file_name().to_string_lossy().into_owned();
+            let path = if dir.is_empty() {
+                name
+            } else {
+                format!("{}/{}", dir, name)
+            };
+            // Follows symlinks, like the transfers do
+            let metadata = fs::metadata(item.path())?;
+            if !selected(&path, metadata.is_dir(), options) {
+                continue;
+            }
+            if metadata.is_dir() {
+                pending.push(path.clone());
+            } else if !metadata.is_file() {
+                continue;
+            }
+
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs() as u32)
+                .unwrap_or(0);
+            entries.insert(
+                path,
+                Entry {
+                    is_dir: metadata.is_dir(),
+                    size: metadata.len(),
+                    mtime,
+                },
+            );
+        }
+    }
+
+    Ok(entries)
+}
+
+async fn walk_remote(
+    sftp: &Sftp,
+    root: &str,
+    options: &SyncOptions,
+) -> anyhow::Result<BTreeMap<String, Entry>> {
+    let mut entries = BTreeMap::new();
+    let mut pending = vec![String::new()];
+
+    while let Some(dir) = pending.pop() {
+        for item in sftp.sftp.read_dir(join_remote(root, &dir)).await? {
+            let name = item.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = if dir.is_empty() {
+                name
+            } else {
+                format!("{}/{}", dir, name)
+            };
+            let mut metadata: FileAttributes = item.metadata();
+            if metadata.file_type().is_symlink() {
+                metadata = sftp.sftp.metadata(join_remote(root, &path)).await?;
+            }
+            let is_dir = metadata.file_type().is_dir();
+            if !selected(&path, is_dir, options) {
+                continue;
+            }
+            if is_dir {
+                pending.push(path.clone());
+            } else if !metadata.file_type().is_file() {
+                continue;
+            }
+
+            entries.insert(
+                path,
+                Entry {
+                    is_dir,
+                    size: metadata.size.unwrap_or(0),
+                    mtime: metadata.mtime.unwrap_or(0),
+                },
+            );
+        }
+    }
+
+    Ok(entries)
+}
+
+fn local_checksums(root: &Path, paths: &[String]) -> anyhow::Result<HashMap<String, String>> {
+    let mut sums = HashMap::new();
+    for path in paths {
+        let mut file = fs::File::open(root.join(path))?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        sums.insert(path.clone(), format!("{:x}", hasher.finalize()));
+    }
+    Ok(sums)
+}
+
+/// Runs `sha256sum` on the server, a few files at a time to keep the
+/// command line short.
+async fn remote_checksums(
+    sftp: &Sftp,
+    root: &str,
+    paths: &[String],
+) -> anyhow::Result<HashMap<String, String>> {
+    let mut sums = HashMap::new();
+    for chunk in paths.chunks(100) {
+        let full: HashMap<String, &String> = chunk
+            .iter()
+            .map(|path| (join_remote(root, path), path))
+            .collect();
+        let args: Vec<String> = full.keys().map(|path| shell_quote(path)).collect();
+        let (code, stdout) = sftp
+            .session
+            .output(&format!("sha256sum -- {}", args.join(" ")))
+            .await?;
+        if code == 127 {
+            anyhow::bail!("😿 sha256sum is not available on the server, sync without --checksum.")
+        }
+
+        for line in String::from_utf8_lossy(&stdout).lines() {
+            // Names with unusual characters are escaped, those count as changed
+            if let Some((sum, path)) = line.split_once("  ")
+                && let Some(path) = full.get(path)
+            {
+                sums.insert(path.to_string(), sum.to_string());
+            }
+        }
+    }
+    Ok(sums)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(include: &[&str], exclude: &[&str]) -> SyncOptions {
+        SyncOptions {
+            include: include.iter().map(|p| p.to_string()).collect(),
+            exclude: exclude.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn everything_is_selected_by_default() {
+        let options = options(&[], &[]);
+        assert!(selected("app.log", false, &options));
+        assert!(selected("a/b/c.txt", false, &options));
+    }
+
+    #[test]
+    fn patterns_without_slash_match_any_component() {
+        let options = options(&["*.log"], &["node_modules"]);
+        assert!(selected("logs/app.log", false, &options));
+        assert!(!selected("logs/app.txt", false, &options));
+        assert!(!selected("web/node_modules/x.log", false, &options));
+        assert!(!selected("node_modules", true, &options));
+    }
+
+    #[test]
+    fn patterns_with_slash_match_the_whole_path() {
+        let options = options(&["/logs/*.log"], &[]);
+        assert!(selected("logs/app.log", false, &options));
+        assert!(!selected("old/logs/app.log", false, &options));
+    }
+
+    #[test]
+    fn directories_are_walked_unless_excluded() {
+        let options = options(&["*.log"], &["tmp"]);
+        assert!(selected("logs", true, &options));
+        assert!(!selected("tmp", true, &options));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{common::expand_path_template, model::Server};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// Tees session output into a log file, optionally without ANSI escape
+/// sequences and with every line timestamped.
+pub(crate) struct Transcript {
+    writer: BufWriter<File>,
+    strip_ansi: bool,
+    timestamp: bool,
+    line_start: bool,
+    escape: Escape,
+}
+
+/// Where the ANSI stripper is, sequences may be split across writes.
+#[derive(Clone, Copy, PartialEq)]
+enum Escape {
+    None,
+    Start,
+    Csi,
+    Osc,
+    OscEnd,
+}
+
+impl Transcript {
+    /// Opens the server's `log_file` for appending, `None` when it has none.
+    pub(crate) fn open(server: &Server) -> anyhow::Result<Option<Self>> {
+        let Some(template) = &server.log_file else {
+            return Ok(None);
+        };
+
+        let path = expand_path_template(template, server);
+        if let Some(parent) = Path::new(&path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Sessions may show secrets, keep the log to ourselves
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("😿 Failed to open log file {}: {}", path, e))?;
+
+        Ok(Some(Self {
+            writer: BufWriter::new(file),
+            strip_ansi: server.log_strip_ansi.unwrap_or(false),
+            timestamp: server.log_timestamp.unwrap_or(false),
+            line_start: true,
+            escape: Escape::None,
+        }))
+    }
+
+    pub(crate) fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        for &b in data {
+            if self.strip_ansi && self.skip(b) {
+                continue;
+            }
+            if self.timestamp && self.line_start {
+                let now = chrono::Local::now().format("[%Y-%m-%d %H:%M:%S] ");
+                write!(self.writer, "{}", now)?;
+            }
+            self.writer.write_all(&[b])?;
+            self.line_start = b == b'\n';
+        }
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Advances the escape sequence state, true when `b` must not be logged.
+    fn skip(&mut self, b: u8) -> bool {
+        self.escape = match (self.escape, b) {
+            (Escape::None, 0x1b) => Escape::Start,
+            // Carriage returns only make sense on a terminal
+            (Escape::None, b'\r') => return true,
+            (Escape::None, _) => return false,
+            (Escape::Start, b'[') => Escape::Csi,
+            (Escape::Start, b']') => Escape::Osc,
+            (Escape::Start, _) => Escape::None,
+            (Escape::Csi, 0x40..=0x7e) => Escape::None,
+            (Escape::Csi, _) => Escape::Csi,
+            (Escape::Osc, 0x07) => Escape::None,
+            (Escape::Osc, 0x1b) => Escape::OscEnd,
+            (Escape::Osc, _) => Escape::Osc,
+            (Escape::OscEnd, _) => Escape::None,
+        };
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What ends up in the log after writing `chunks` one after another.
+    fn logged(chunks: &[&[u8]], strip_ansi: bool) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.log");
+        let mut server = Server::new("10.0.0.1".to_string());
+        server.log_file = Some(path.to_string_lossy().into_owned());
+        server.log_strip_ansi = Some(strip_ansi);
+
+        let mut transcript = Transcript::open(&server).unwrap().unwrap();
+        for chunk in chunks {
+            transcript.write(chunk).unwrap();
+        }
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn ansi_sequences_are_stripped() {
+        let output = b"\x1b[1;32mok\x1b[0m\r\n\x1b]0;title\x07done\x1b]2;t\x1b\\\n";
+        assert_eq!(logged(&[output], true), "ok\ndone\n");
+    }
+
+    #[test]
+    fn sequences_split_across_writes_are_stripped() {
+        assert_eq!(
+            logged(&[b"a\x1b", b"[3", b"1mb\x1b]0;", b"x\x07c"], true),
+            "abc"
+        );
+    }
+
+    #[test]
+    fn output_is_kept_as_is_without_stripping() {
+        assert_eq!(logged(&[b"\x1b[1mok\r\n"], false), "\x1b[1mok\r\n");
+    }
+}